@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::Write;
+use std::collections::{HashMap, HashSet};
+
+extern crate bio;
+extern crate clap;
+extern crate digest;
+extern crate log;
+extern crate simple_logger;
+
+use clap::{Arg, App};
+use bio::io::fasta;
+use log::{info, warn};
+
+use digest::{Flank, digest_gene, find_collisions, ambiguous_groups};
+
+
+struct Params<'a> {
+    enzyme: &'a [u8],
+    left_context: &'a [u8],
+    right_context: &'a [u8],
+    cut_offset: Option<usize>,
+    max_mismatch: usize,
+    canonical: bool,
+}
+
+
+fn digest_all(genes: &[(String, Vec<u8>)], flanking: usize, params: &Params) -> Vec<Flank> {
+    genes.iter().flat_map(|(name, seq)| digest_gene(
+        name, seq, params.left_context, params.right_context,
+        params.enzyme, flanking, params.cut_offset)).collect()
+}
+
+
+/// Genes having at least one flank, and genes having flank collided with other genes.
+fn flanked_and_colliding(genes: &[(String, Vec<u8>)], flanking: usize, params: &Params) -> (HashSet<String>, HashSet<String>) {
+    let flanks = digest_all(genes, flanking, params);
+    let collisions = find_collisions(&flanks, params.max_mismatch, params.canonical);
+    let flanked = flanks.iter().map(|f| f.gene.clone()).collect();
+    let mut colliding = HashSet::new();
+    for c in collisions {
+        colliding.insert(flanks[c.a].gene.clone());
+        colliding.insert(flanks[c.b].gene.clone());
+    }
+    (flanked, colliding)
+}
+
+
+/// Minimal flanking length make each ambiguous gene unique, and the suggested flanking length
+/// for the whole library. A gene is resolved only when it still has flank at that length and
+/// none of its flanks collide, genes have their flanks run out of the construct are not.
+fn min_unique_lengths(
+        genes: &[(String, Vec<u8>)], ambiguous: &HashSet<String>,
+        flanking: usize, max_flanking: usize, params: &Params) -> (HashMap<String, usize>, Option<usize>) {
+    let mut unresolved = ambiguous.clone();
+    let mut min_unique: HashMap<String, usize> = HashMap::new();
+    let mut suggested = if ambiguous.is_empty() { Some(flanking) } else { None };
+    for len in (flanking + 1)..=max_flanking {
+        if unresolved.is_empty() && suggested.is_some() { break }
+        let (flanked, colliding) = flanked_and_colliding(genes, len, params);
+        if colliding.is_empty() && suggested.is_none() && ambiguous.is_subset(&flanked) {
+            suggested = Some(len)
+        }
+        unresolved.retain(|g| {
+            if colliding.contains(g) || !flanked.contains(g) { return true }
+            min_unique.insert(g.clone(), len);
+            false
+        });
+    }
+    (min_unique, suggested)
+}
+
+
+fn main() {
+    simple_logger::init().unwrap();
+
+    let matches = App::new("Check whether genes in library can be distinguished by their flanks.")
+        .arg(Arg::with_name("library")
+             .required(true)
+             .help("Fasta file of bait and prey ORF sequences."))
+        .arg(Arg::with_name("enzyme")
+             .short("e")
+             .long("enzyme")
+             .takes_value(true)
+             .help("Enzyme recognize site, same as used in paircnt."))
+        .arg(Arg::with_name("flanking")
+             .short("f")
+             .long("flanking")
+             .takes_value(true)
+             .help("Flanking length."))
+        .arg(Arg::with_name("left_context")
+             .long("left_context")
+             .takes_value(true)
+             .help("Vector sequence at 5' of ORF."))
+        .arg(Arg::with_name("right_context")
+             .long("right_context")
+             .takes_value(true)
+             .help("Vector sequence at 3' of ORF."))
+        .arg(Arg::with_name("cut_offset")
+             .long("cut_offset")
+             .takes_value(true)
+             .help("Distance from enzyme site to the cut site, for type IIS enzyme. Flanks are truncated to it."))
+        .arg(Arg::with_name("max_mismatch")
+             .short("m")
+             .long("max_mismatch")
+             .takes_value(true)
+             .help("Flanks within this number of mismatches are taken as collision."))
+        .arg(Arg::with_name("max_flanking")
+             .long("max_flanking")
+             .takes_value(true)
+             .help("Max flanking length tried for suggesting the minimal unique flanking length."))
+        .arg(Arg::with_name("oriented")
+             .long("oriented")
+             .help("Flanks are not collided with reverse complement of others, for paircnt --oriented."))
+        .arg(Arg::with_name("output_prefix")
+             .short("o")
+             .long("output_prefix")
+             .required(true)
+             .takes_value(true)
+             .help("Prefix of output files."))
+        .get_matches();
+
+    let lib_path = matches.value_of("library").unwrap();
+    let enzyme = matches.value_of("enzyme").unwrap_or("GTTGGA").to_uppercase();
+    let flanking: usize = matches.value_of("flanking").unwrap_or("13").parse().unwrap();
+    let mut max_flanking: usize = matches.value_of("max_flanking").unwrap_or("32").parse().unwrap();
+    let out_prefix = matches.value_of("output_prefix").unwrap();
+    let params = Params {
+        enzyme: enzyme.as_bytes(),
+        left_context: matches.value_of("left_context").unwrap_or("").as_bytes(),
+        right_context: matches.value_of("right_context").unwrap_or("").as_bytes(),
+        cut_offset: matches.value_of("cut_offset").map(|c| c.parse().unwrap()),
+        max_mismatch: matches.value_of("max_mismatch").unwrap_or("0").parse().unwrap(),
+        canonical: !matches.is_present("oriented"),
+    };
+    if let Some(c) = params.cut_offset {
+        if c < max_flanking { max_flanking = c }
+    }
+
+    let mut genes: Vec<(String, Vec<u8>)> = vec![];
+    for rec in fasta::Reader::from_file(lib_path).unwrap().records() {
+        let rec = rec.unwrap();
+        genes.push((rec.id().to_string(), rec.seq().to_vec()));
+    }
+
+    let flanks = digest_all(&genes, flanking, &params);
+    let collisions = find_collisions(&flanks, params.max_mismatch, params.canonical);
+    let groups = ambiguous_groups(&flanks, &collisions);
+    let genes_with_flank: HashSet<&str> = flanks.iter().map(|f| f.gene.as_str()).collect();
+    info!("{} flanks were extracted from {} genes, {} genes have no flank.",
+        flanks.len(), genes.len(), genes.len() - genes_with_flank.len());
+
+    let coll_path = format!("{}.collisions.tsv", out_prefix);
+    info!("Write {} collisions to tsv file: {}", collisions.len(), coll_path);
+    let mut coll_file = File::create(coll_path).unwrap();
+    let _ = writeln!(coll_file, "gene1\tside1\tseq1\tgene2\tside2\tseq2\tmismatches\trevcomp");
+    for c in &collisions {
+        let (a, b) = (&flanks[c.a], &flanks[c.b]);
+        let _ = writeln!(coll_file, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            a.gene, a.side, String::from_utf8_lossy(&a.seq),
+            b.gene, b.side, String::from_utf8_lossy(&b.seq),
+            c.n_mismatch, c.revcomp);
+    }
+
+    let ambiguous: HashSet<String> = groups.iter().flatten().cloned().collect();
+    let (min_unique, suggested) = min_unique_lengths(&genes, &ambiguous, flanking, max_flanking, &params);
+
+    let group_path = format!("{}.groups.tsv", out_prefix);
+    info!("Write {} groups of ambiguous genes to tsv file: {}", groups.len(), group_path);
+    let mut group_file = File::create(group_path).unwrap();
+    let _ = writeln!(group_file, "gene\tgroup\tmin_unique_flanking");
+    for g in &groups {
+        let name = g.join("|");
+        for gene in g {
+            let min_len = match min_unique.get(gene) {
+                Some(l) => l.to_string(),
+                None => "NA".to_string(),
+            };
+            let _ = writeln!(group_file, "{}\t{}\t{}", gene, name, min_len);
+        }
+    }
+
+    info!("{} of {} genes can't be distinguished at flanking length {}.", ambiguous.len(), genes.len(), flanking);
+    match suggested {
+        Some(l) => info!("Suggested minimal flanking length for uniqueness: {}", l),
+        None => warn!("Genes still can't be distinguished at flanking length {}.", max_flanking),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARED: &str = "CGTAGCTAGCTAG";
+
+    fn gene(name: &str, prefix: &str) -> (String, Vec<u8>) {
+        (name.to_string(), format!("{}{}GTTGGA", prefix, SHARED).into_bytes())
+    }
+
+    fn params() -> Params<'static> {
+        Params {
+            enzyme: b"GTTGGA", left_context: b"", right_context: b"",
+            cut_offset: None, max_mismatch: 0, canonical: true,
+        }
+    }
+
+    fn names(genes: &[&str]) -> HashSet<String> {
+        genes.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn test_min_unique_lengths() {
+        let genes = vec![gene("A", "AAAA"), gene("B", "AAAC")];
+        let (min_unique, suggested) = min_unique_lengths(&genes, &names(&["A", "B"]), 13, 32, &params());
+        assert_eq!(min_unique["A"], 14);
+        assert_eq!(min_unique["B"], 14);
+        assert_eq!(suggested, Some(14));
+    }
+
+    #[test]
+    fn test_min_unique_lengths_flank_run_out() {
+        // C has no flank longer than 13, it is never resolved
+        let genes = vec![gene("A", "AAAA"), gene("B", "AAAC"), gene("C", "")];
+        let (min_unique, suggested) = min_unique_lengths(&genes, &names(&["A", "B", "C"]), 13, 32, &params());
+        assert_eq!(min_unique.get("A"), Some(&14));
+        assert_eq!(min_unique.get("B"), Some(&14));
+        assert_eq!(min_unique.get("C"), None);
+        assert_eq!(suggested, None);
+
+        // identical ORFs only collide or run out
+        let orf = b"ACGATCGATTACGCATGCAAGTTGGACATG".to_vec();
+        let genes = vec![("A".to_string(), orf.clone()), ("B".to_string(), orf)];
+        let (min_unique, suggested) = min_unique_lengths(&genes, &names(&["A", "B"]), 13, 39, &params());
+        assert!(min_unique.is_empty());
+        assert_eq!(suggested, None);
+    }
+
+    #[test]
+    fn test_min_unique_lengths_no_ambiguous() {
+        let genes = vec![gene("A", "")];
+        let (min_unique, suggested) = min_unique_lengths(&genes, &HashSet::new(), 13, 32, &params());
+        assert!(min_unique.is_empty());
+        assert_eq!(suggested, Some(13));
+    }
+}
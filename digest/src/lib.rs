@@ -0,0 +1,259 @@
+extern crate bio;
+
+use std::fmt;
+use std::collections::HashMap;
+
+use bio::alphabets::dna::revcomp;
+
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    // flank at 5' of enzyme site, as the left flank of linker in paircnt
+    Left,
+    // flank at 3' of reverse complement enzyme site, as the right flank of linker
+    Right,
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Side::Left => write!(f, "left"),
+            Side::Right => write!(f, "right"),
+        }
+    }
+}
+
+
+/// Expected flanking sequence of a gene, adjacent to an enzyme site.
+pub struct Flank {
+    pub gene: String,
+    pub side: Side,
+    // 0-based start position of flank in the construct
+    pub start: usize,
+    // 0-based start offset relative to the first base of ORF, negative if upstream of ORF
+    pub orf_start: isize,
+    // the enzyme site is inside the ORF instead of vector context
+    pub internal: bool,
+    pub seq: Vec<u8>,
+}
+
+
+/// All (0-based) positions of site in sequence, case insensitive.
+pub fn find_sites(seq: &[u8], site: &[u8]) -> Vec<usize> {
+    if site.is_empty() || seq.len() < site.len() { return vec![] }
+    (0..=(seq.len() - site.len())).filter(|i| {
+        seq[*i..*i+site.len()].iter().zip(site).all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }).collect()
+}
+
+
+/// Digest the construct (ORF inserted between vector contexts) in silico.
+/// The left flanks are sequences just before each enzyme site, the right flanks
+/// are just after each reverse complement enzyme site, same as how paircnt
+/// extracts flanks around the `enzyme + linker + revcomp(enzyme)` pattern.
+/// For type IIS enzymes, flanks are truncated to the `cut_offset` from site.
+/// Flanks run out of the construct are dropped.
+pub fn digest_gene(
+        gene: &str, orf: &[u8],
+        left_context: &[u8], right_context: &[u8],
+        enzyme: &[u8], flanking: usize, cut_offset: Option<usize>) -> Vec<Flank> {
+    let construct: Vec<u8> = [left_context, orf, right_context].concat()
+        .iter().map(|b| b.to_ascii_uppercase()).collect();
+    let orf_s = left_context.len();
+    let orf_e = orf_s + orf.len();
+    let len = match cut_offset {
+        Some(c) if c < flanking => c,
+        _ => flanking,
+    };
+    let e_len = enzyme.len();
+    let overlap_orf = |site: usize| site < orf_e && site + e_len > orf_s;
+    let mut flanks = vec![];
+    for site in find_sites(&construct, enzyme) {
+        if site < len { continue }
+        let start = site - len;
+        flanks.push(Flank {
+            gene: gene.to_string(),
+            side: Side::Left,
+            start,
+            orf_start: start as isize - orf_s as isize,
+            internal: overlap_orf(site),
+            seq: construct[start..site].to_vec(),
+        });
+    }
+    for site in find_sites(&construct, &revcomp(enzyme)) {
+        let start = site + e_len;
+        if start + len > construct.len() { continue }
+        flanks.push(Flank {
+            gene: gene.to_string(),
+            side: Side::Right,
+            start,
+            orf_start: start as isize - orf_s as isize,
+            internal: overlap_orf(site),
+            seq: construct[start..start+len].to_vec(),
+        });
+    }
+    flanks
+}
+
+
+fn hamming(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y || !matches!(x, b'A' | b'C' | b'G' | b'T')).count()
+}
+
+
+/// Two flanks of different genes can't be distinguished.
+pub struct Collision {
+    pub a: usize,
+    pub b: usize,
+    pub n_mismatch: usize,
+    // flank `a` collides with the reverse complement of `b`
+    pub revcomp: bool,
+}
+
+
+/// Find the flank pairs (of different genes) within `max_mismatch` mismatches.
+/// If `canonical`, a flank also collides with the reverse complement of others,
+/// as the canonical keys in paircnt.
+/// Flanks are split into `max_mismatch + 1` segments, candidate pairs share
+/// at least one identical segment, then verified by hamming distance.
+pub fn find_collisions(flanks: &[Flank], max_mismatch: usize, canonical: bool) -> Vec<Collision> {
+    let n_seg = max_mismatch + 1;
+    let mut index: HashMap<(usize, usize, &[u8]), Vec<usize>> = HashMap::new();
+    for (i, f) in flanks.iter().enumerate() {
+        let step = f.seq.len() / n_seg;
+        if step == 0 { continue }
+        for s in 0..n_seg {
+            let seg = &f.seq[s*step..(s+1)*step];
+            index.entry((f.seq.len(), s, seg)).or_default().push(i);
+        }
+    }
+
+    let mut found: HashMap<(usize, usize), (usize, bool)> = HashMap::new();
+    for (a, fa) in flanks.iter().enumerate() {
+        let step = fa.seq.len() / n_seg;
+        if step == 0 { continue }
+        let mut queries = vec![(fa.seq.clone(), false)];
+        if canonical { queries.push((revcomp(&fa.seq), true)) }
+        for (q, rc) in queries {
+            for s in 0..n_seg {
+                let seg = &q[s*step..(s+1)*step];
+                let candidates = match index.get(&(q.len(), s, seg)) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                for b in candidates {
+                    if *b <= a || flanks[*b].gene == fa.gene { continue }
+                    let n_mismatch = hamming(&q, &flanks[*b].seq);
+                    if n_mismatch > max_mismatch { continue }
+                    let e = found.entry((a, *b)).or_insert((n_mismatch, rc));
+                    if n_mismatch < e.0 { *e = (n_mismatch, rc) }
+                }
+            }
+        }
+    }
+    let mut collisions: Vec<Collision> = found.into_iter()
+        .map(|((a, b), (n_mismatch, revcomp))| Collision { a, b, n_mismatch, revcomp })
+        .collect();
+    collisions.sort_by_key(|c| (c.a, c.b));
+    collisions
+}
+
+
+/// Group genes connected by collisions, return the groups with more than one gene.
+pub fn ambiguous_groups(flanks: &[Flank], collisions: &[Collision]) -> Vec<Vec<String>> {
+    let mut parent: HashMap<&str, &str> = HashMap::new();
+    fn find<'a>(parent: &mut HashMap<&'a str, &'a str>, x: &'a str) -> &'a str {
+        let p = *parent.entry(x).or_insert(x);
+        if p == x { return x }
+        let root = find(parent, p);
+        parent.insert(x, root);
+        root
+    }
+    for c in collisions {
+        let ra = find(&mut parent, &flanks[c.a].gene);
+        let rb = find(&mut parent, &flanks[c.b].gene);
+        if ra != rb { parent.insert(ra, rb); }
+    }
+    let genes: Vec<&str> = parent.keys().cloned().collect();
+    let mut groups: HashMap<&str, Vec<String>> = HashMap::new();
+    for g in genes {
+        let root = find(&mut parent, g);
+        groups.entry(root).or_default().push(g.to_string());
+    }
+    let mut groups: Vec<Vec<String>> = groups.into_values().map(|mut g| { g.sort(); g }).collect();
+    groups.sort();
+    groups
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flank(gene: &str, seq: &str) -> Flank {
+        Flank {
+            gene: gene.to_string(), side: Side::Left,
+            start: 0, orf_start: 0, internal: false,
+            seq: seq.as_bytes().to_vec(),
+        }
+    }
+
+    fn pairs(collisions: &[Collision]) -> Vec<(usize, usize, usize, bool)> {
+        collisions.iter().map(|c| (c.a, c.b, c.n_mismatch, c.revcomp)).collect()
+    }
+
+    #[test]
+    fn test_find_collisions_exact() {
+        let flanks = vec![
+            flank("A", "ACGTTGCAAGCTA"),
+            flank("B", "ACGTTGCAAGCTA"),
+            flank("B", "TTTTTGGGGCCCC"),
+            // same gene is not a collision
+            flank("A", "TTTTTGGGGCCCC"),
+        ];
+        assert_eq!(pairs(&find_collisions(&flanks, 0, false)), vec![(0, 1, 0, false), (2, 3, 0, false)]);
+    }
+
+    #[test]
+    fn test_find_collisions_mismatch() {
+        let flanks = vec![
+            flank("A", "ACGTTGCAAGCTA"),
+            flank("B", "ACGTAGCAAGCTA"),
+            flank("C", "ACCTAGCAAGCTT"),
+        ];
+        assert!(find_collisions(&flanks, 0, false).is_empty());
+        assert_eq!(pairs(&find_collisions(&flanks, 1, false)), vec![(0, 1, 1, false)]);
+        assert_eq!(pairs(&find_collisions(&flanks, 3, false)),
+                   vec![(0, 1, 1, false), (0, 2, 3, false), (1, 2, 2, false)]);
+        // N never matches
+        let flanks = vec![flank("A", "ACGTNGCAAGCTA"), flank("B", "ACGTNGCAAGCTA")];
+        assert!(find_collisions(&flanks, 0, false).is_empty());
+        assert_eq!(pairs(&find_collisions(&flanks, 1, false)), vec![(0, 1, 1, false)]);
+    }
+
+    #[test]
+    fn test_find_collisions_revcomp() {
+        let flanks = vec![flank("A", "AACGTTGCAAGCT"), flank("B", "AGCTTGCAACGTT")];
+        assert!(find_collisions(&flanks, 0, false).is_empty());
+        assert_eq!(pairs(&find_collisions(&flanks, 0, true)), vec![(0, 1, 0, true)]);
+    }
+
+    #[test]
+    fn test_ambiguous_groups() {
+        let flanks = vec![
+            flank("A", "ACGTTGCAAGCTA"),
+            flank("B", "ACGTTGCAAGCTA"),
+            flank("B", "TTTTTGGGGCCCC"),
+            flank("C", "TTTTTGGGGCCCC"),
+            flank("D", "GGGGAAAATTTTC"),
+            flank("E", "GGGGAAAATTTTC"),
+            flank("F", "CATCATCATCATC"),
+        ];
+        let collisions = find_collisions(&flanks, 0, false);
+        assert_eq!(ambiguous_groups(&flanks, &collisions), vec![
+            vec!["A".to_string(), "B".to_string(), "C".to_string()],
+            vec!["D".to_string(), "E".to_string()],
+        ]);
+        assert!(ambiguous_groups(&flanks, &[]).is_empty());
+    }
+}
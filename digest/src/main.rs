@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::Write;
+
+extern crate bio;
+extern crate clap;
+extern crate digest;
+extern crate log;
+extern crate simple_logger;
+
+use clap::{Arg, App};
+use bio::io::fasta;
+use log::{info, warn};
+
+use digest::digest_gene;
+
+
+fn main() {
+    simple_logger::init().unwrap();
+
+    let matches = App::new("Digest ORF library in silico, produce the expected flanking sequences.")
+        .arg(Arg::with_name("orf")
+             .required(true)
+             .help("Fasta file of ORF sequences."))
+        .arg(Arg::with_name("enzyme")
+             .short("e")
+             .long("enzyme")
+             .takes_value(true)
+             .help("Enzyme recognize site, same as used in paircnt."))
+        .arg(Arg::with_name("flanking")
+             .short("f")
+             .long("flanking")
+             .takes_value(true)
+             .help("Flanking length."))
+        .arg(Arg::with_name("left_context")
+             .long("left_context")
+             .takes_value(true)
+             .help("Vector sequence at 5' of ORF."))
+        .arg(Arg::with_name("right_context")
+             .long("right_context")
+             .takes_value(true)
+             .help("Vector sequence at 3' of ORF."))
+        .arg(Arg::with_name("cut_offset")
+             .long("cut_offset")
+             .takes_value(true)
+             .help("Distance from enzyme site to the cut site, for type IIS enzyme. Flanks are truncated to it."))
+        .arg(Arg::with_name("output_prefix")
+             .short("o")
+             .long("output_prefix")
+             .required(true)
+             .takes_value(true)
+             .help("Prefix of output files."))
+        .get_matches();
+
+    let orf_path = matches.value_of("orf").unwrap();
+    let enzyme = matches.value_of("enzyme").unwrap_or("GTTGGA").to_uppercase();
+    let flanking: usize = matches.value_of("flanking").unwrap_or("13").parse().unwrap();
+    let left_context = matches.value_of("left_context").unwrap_or("");
+    let right_context = matches.value_of("right_context").unwrap_or("");
+    let cut_offset: Option<usize> = matches.value_of("cut_offset").map(|c| c.parse().unwrap());
+    let out_prefix = matches.value_of("output_prefix").unwrap();
+
+    if let Some(c) = cut_offset {
+        if c < flanking {
+            warn!("Cut offset {} is shorter than flanking length {}, flanks are truncated.", c, flanking);
+        }
+    }
+
+    let fa_path = format!("{}.fa", out_prefix);
+    let tsv_path = format!("{}.tsv", out_prefix);
+    let mut fa_out = fasta::Writer::new(File::create(fa_path.clone()).unwrap());
+    let mut tsv_out = File::create(tsv_path.clone()).unwrap();
+    let _ = writeln!(tsv_out, "gene\tside\tstart\torf_start\tinternal\tseq");
+
+    let mut n_genes = 0;
+    let mut n_flanks = 0;
+    let mut no_flank = vec![];
+    let mut with_internal = vec![];
+    for rec in fasta::Reader::from_file(orf_path).unwrap().records() {
+        let rec = rec.unwrap();
+        n_genes += 1;
+        let flanks = digest_gene(
+            rec.id(), rec.seq(),
+            left_context.as_bytes(), right_context.as_bytes(),
+            enzyme.as_bytes(), flanking, cut_offset);
+        if flanks.is_empty() { no_flank.push(rec.id().to_string()) }
+        if flanks.iter().any(|f| f.internal) { with_internal.push(rec.id().to_string()) }
+        for f in flanks {
+            let desc = format!("side={};start={};orf_start={};internal={}",
+                f.side, f.start + 1, f.orf_start, f.internal);
+            let _ = fa_out.write(&f.gene, Some(&desc), &f.seq);
+            let _ = writeln!(tsv_out, "{}\t{}\t{}\t{}\t{}\t{}",
+                f.gene, f.side, f.start + 1, f.orf_start, f.internal,
+                String::from_utf8_lossy(&f.seq));
+            n_flanks += 1;
+        }
+    }
+
+    info!("Totally {} flanks were produced from {} genes.", n_flanks, n_genes);
+    if !no_flank.is_empty() {
+        warn!("{} genes have no complete flank: {}", no_flank.len(), no_flank.join(","));
+    }
+    if !with_internal.is_empty() {
+        warn!("{} genes have enzyme site inside ORF: {}", with_internal.len(), with_internal.join(","));
+    }
+    info!("Write flank reference to fasta file: {}", fa_path);
+    info!("Write flank positions to tsv file: {}", tsv_path);
+}
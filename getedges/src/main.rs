@@ -1,113 +1,230 @@
 use std::fs::File;
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
-use std::str::FromStr;
 use std::fmt;
+use std::process;
 
 extern crate clap;
+extern crate flate2;
+extern crate regex;
 extern crate log;
 extern crate simple_logger;
 
+mod mapper;
+mod multihit;
+mod roles;
+mod sam;
+
 use clap::{Arg, App};
-use log::{info};
+use log::{info, warn, error};
+
+use mapper::{Mapper, load_fasta, load_fastq};
+use multihit::{MultiHit, PairCnt, group_edges, em_edges};
+use roles::{Role, Roles};
+use sam::{SamRecord, read_sam};
 
 
 enum Node {
-    Bait(String),
-    Prey(String),
+    // the gene, or the candidate genes of the ambiguous flank
+    Bait(Vec<String>),
+    Prey(Vec<String>),
     NotValid(NotValidType),
 }
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let node_str = match self {
-            Node::Bait(name) => format!("Bait:{}", name),
-            Node::Prey(name) => format!("Prey:{}", name),
+            Node::Bait(genes) => format!("Bait:{}", genes.join("|")),
+            Node::Prey(genes) => format!("Prey:{}", genes.join("|")),
             Node::NotValid(nvtp) => match nvtp {
-                NotValidType::NotFound => format!("NotFound"),
+                NotValidType::NotFound => "NotFound".to_string(),
                 NotValidType::MapqTooSmall(s) => format!("MAPQTooSmall:{}", s),
                 NotValidType::TooManyMisMatch(s) => format!("TooManyMisMatch:{}", s),
-                NotValidType::TooManyAligned(s) => format!("TooManyAligned:{}", s),
+                NotValidType::TooManyAligned(genes) => format!("TooManyAligned:{}:{}", genes.len(), genes.join(",")),
+                NotValidType::UnknownRole(gene) => format!("UnknownRole:{}", gene),
             },
         };
         write!(f, "{}", node_str)
     }
 }
 
+impl Node {
+    fn role(&self) -> Option<Role> {
+        match self {
+            Node::Bait(_) => Some(Role::Bait),
+            Node::Prey(_) => Some(Role::Prey),
+            Node::NotValid(_) => None,
+        }
+    }
+}
+
+
+/// Whether the roles of an oriented pair agree with the design,
+/// `side1` is the role of gene on the bait side.
+fn orientation(side1: Option<Role>, side2: Option<Role>) -> &'static str {
+    match (side1, side2) {
+        (Some(Role::Bait), Some(Role::Prey)) => "concordant",
+        (Some(Role::Prey), Some(Role::Bait)) => "reversed",
+        // both genes have the same role
+        (Some(_), Some(_)) => "discordant",
+        _ => "not_valid",
+    }
+}
+
 enum NotValidType {
     NotFound,
     MapqTooSmall(u8),
-    TooManyMisMatch(u8),
-    TooManyAligned(u8),
+    TooManyMisMatch(u32),
+    // the candidate genes
+    TooManyAligned(Vec<String>),
+    // the reference is neither bait nor prey
+    UnknownRole(String),
 }
 
-struct SamRec<'a> {
-    qname: u64,
-    rname: &'a str,
+/// Summary of the alignments of a query.
+struct SamRec {
+    rname: String,
     mapq: u8,
-    n_mismatch: u8,
-    n_aligned: u8,
+    n_mismatch: u32,
+    // distinct genes of the primary and alternative hits, primary first
+    genes: Vec<String>,
 }
 
-
-fn parse_bwa_sam_rec<'a>(line: &'a String) -> SamRec<'a> {
-    let items: Vec<&str> = line.split("\t").collect();
-    let qname: u64 = items[0].parse().unwrap();
-    let rname = items[2];
-    let mapq: u8 = items[4].parse().unwrap();
-    let mut nm: u8 = 0;
-    let mut na: u8 = if rname == "*" {0} else {1};
-    if items.len() >= 12 {
-        for i in 12..items.len() {
-            if items[i].starts_with("NM") {
-                let fields: Vec<&str> = items[i].split(":").collect();
-                nm = fields[2].parse().unwrap();
-                continue
-            }
-            if items[i].starts_with("XA") {
-                let fields: Vec<&str> = items[i].split(";").collect();
-                na += fields.len() as u8;
-                break
+impl SamRec {
+    fn from_record(rec: &SamRecord) -> Self {
+        let unmapped = rec.is_unmapped();
+        // NM is the edit distance, nM is used by some aligners (like STAR) instead
+        let nm = rec.tag("NM").or_else(|| rec.tag("nM")).and_then(|v| v.as_int()).unwrap_or(0);
+        let mut sam_rec = SamRec {
+            rname: if unmapped { "*".to_string() } else { rec.rname.clone() },
+            mapq: rec.mapq,
+            n_mismatch: nm.max(0) as u32,
+            genes: vec![],
+        };
+        if unmapped { return sam_rec }
+        sam_rec.add_gene(&rec.rname);
+        // alternative hits of bwa (XA) and chimeric alignments (SA), in `rname,pos,...;` entries
+        for tag in &["XA", "SA"] {
+            if let Some(hits) = rec.tag(tag).and_then(|v| v.as_str()) {
+                for hit in hits.split(';').filter(|h| !h.is_empty()) {
+                    sam_rec.add_gene(hit.split(',').next().unwrap());
+                }
             }
         }
+        sam_rec
     }
-    SamRec {
-        qname: qname,
-        rname: rname,
-        mapq: mapq,
-        n_mismatch: nm,
-        n_aligned: na,
+
+    fn add_gene(&mut self, gene: &str) {
+        if !self.genes.iter().any(|g| g == gene) {
+            self.genes.push(gene.to_string());
+        }
     }
 }
 
 
-fn load_sam(path: &str, th_mapq: u8, th_mismatch: u8, th_aligned: u8) -> HashMap<u64, Node> {
+/// Load the alignments of SAM file, the secondary and supplementary lines
+/// are added to the candidate genes of the primary alignment.
+fn load_sam(path: &str, params: &NodeParams) -> Result<HashMap<String, Node>, String> {
+    let mut primary: HashMap<String, SamRec> = HashMap::new();
+    let mut extra: HashMap<String, Vec<String>> = HashMap::new();
+    for rec in read_sam(path)? {
+        let rec = rec?;
+        if !rec.is_extra() {
+            primary.insert(rec.qname.clone(), SamRec::from_record(&rec));
+        } else if !rec.is_unmapped() {
+            extra.entry(rec.qname).or_default().push(rec.rname);
+        }
+    }
     let mut key2node = HashMap::new();
-    let f = File::open(path).unwrap();
-    let buffered = BufReader::new(f);
-    for line in buffered.lines() {
-        let line = line.unwrap();
-        if line.starts_with("@") { continue }
-        let rec = parse_bwa_sam_rec(&line);
-        let node = if rec.rname == "*" {
-            Node::NotValid(NotValidType::NotFound)
-        } else if rec.mapq < th_mapq {
-            Node::NotValid(NotValidType::MapqTooSmall(rec.mapq))
-        } else if rec.n_mismatch > th_mismatch {
-            Node::NotValid(NotValidType::TooManyMisMatch(rec.n_mismatch))
-        } else if rec.n_aligned > th_aligned {
-            Node::NotValid(NotValidType::TooManyAligned(rec.n_aligned))
+    for (qname, mut rec) in primary {
+        if rec.rname != "*" {
+            for gene in extra.get(&qname).into_iter().flatten() { rec.add_gene(gene) }
+        }
+        let node = to_node(&rec, params);
+        key2node.insert(qname, node);
+    }
+    Ok(key2node)
+}
+
+
+/// Thresholds of alignments and the handling of multi-mapping flanks.
+struct NodeParams {
+    th_mapq: u8,
+    th_mismatch: u32,
+    th_aligned: u32,
+    multi_hit: MultiHit,
+    // max candidate genes of the ambiguous flanks to keep
+    max_candidates: usize,
+    roles: Roles,
+}
+
+
+fn to_node(rec: &SamRec, params: &NodeParams) -> Node {
+    let n_aligned = rec.genes.len();
+    let ambiguous = params.multi_hit != MultiHit::Discard && n_aligned as u32 > params.th_aligned;
+    if rec.rname == "*" {
+        Node::NotValid(NotValidType::NotFound)
+    } else if rec.mapq < params.th_mapq && !ambiguous {
+        // the MAPQ of multi-mapping flanks is low, not checked if they are kept
+        Node::NotValid(NotValidType::MapqTooSmall(rec.mapq))
+    } else if rec.n_mismatch > params.th_mismatch {
+        Node::NotValid(NotValidType::TooManyMisMatch(rec.n_mismatch))
+    } else if n_aligned as u32 > params.th_aligned && (!ambiguous || n_aligned > params.max_candidates) {
+        Node::NotValid(NotValidType::TooManyAligned(rec.genes.clone()))
+    } else {
+        let mut genes = if ambiguous { rec.genes.clone() } else { vec![rec.rname.clone()] };
+        genes.sort();
+        let roles: Vec<Option<Role>> = genes.iter().map(|g| params.roles.role(g)).collect();
+        if let Some(i) = roles.iter().position(|r| r.is_none()) {
+            Node::NotValid(NotValidType::UnknownRole(genes[i].clone()))
+        } else if roles.iter().all(|r| *r == Some(Role::Bait)) {
+            Node::Bait(genes)
+        } else if roles.iter().all(|r| *r == Some(Role::Prey)) {
+            Node::Prey(genes)
         } else {
-            let name = String::from_str(rec.rname).unwrap();
-            if name.starts_with("bait_") {
-                Node::Bait(name)
-            } else if name.starts_with("prey_") {
-                Node::Prey(name)
-            } else {
-                panic!("Gene name should starts with 'prey_' or 'bait_'.")
-            }
+            // candidates of both roles
+            Node::NotValid(NotValidType::TooManyAligned(rec.genes.clone()))
+        }
+    }
+}
+
+
+/// Map the sequences(`.cnt.fq`) to library with the built-in mapper,
+/// mismatches up to `th_mismatch` are allowed.
+fn load_mapped(
+        path_fq: &str, path_lib: &str, params: &NodeParams,
+        map_detail: Option<&str>) -> HashMap<String, Node> {
+    let queries = load_fastq(path_fq);
+    let refs = load_fasta(path_lib);
+    info!("Index library {} with {} sequences.", path_lib, refs.len());
+    let min_len = queries.iter().map(|q| q.1.len()).min().unwrap_or(0);
+    let mapper = Mapper::new(refs, min_len, params.th_mismatch as usize);
+    info!("Map {} sequences to library.", queries.len());
+    let n_short = queries.iter().filter(|q| q.1.len() < mapper.min_query_len()).count();
+    if n_short > 0 {
+        warn!("{} sequences shorter than {} bases are left unmapped.", n_short, mapper.min_query_len());
+    }
+    let mut detail_file = map_detail.map(|p| File::create(p).unwrap());
+
+    let mut key2node = HashMap::new();
+    for (qname, seq) in &queries {
+        let res = mapper.map(seq);
+        let names = mapper.hit_names(&res);
+        let rec = SamRec {
+            rname: names.first().unwrap_or(&"*").to_string(),
+            // like bwa aln, unique hit is 37, multi-hits are 0
+            mapq: if names.len() == 1 { 37 } else { 0 },
+            n_mismatch: res.n_mismatch().unwrap_or(0) as u32,
+            genes: names.iter().map(|n| n.to_string()).collect(),
         };
-        key2node.insert(rec.qname, node);
+        let node = to_node(&rec, params);
+        if let Some(f) = detail_file.as_mut() {
+            let hits: Vec<String> = res.hits.iter().map(|h| format!("{}:{}:{}",
+                mapper.refs[h.ref_idx].name, h.pos + 1, if h.reverse { '-' } else { '+' })).collect();
+            let _ = writeln!(f, "{}\t{}\t{}\t{}\t{}",
+                qname, res.hits.len(), rec.n_mismatch, hits.join(","), node);
+        }
+        key2node.insert(qname.clone(), node);
     }
     key2node
 }
@@ -116,33 +233,52 @@ fn load_sam(path: &str, th_mapq: u8, th_mismatch: u8, th_aligned: u8) -> HashMap
 struct ResCounter {
     // Count for pair numbers and total reads
     n_valid_pair: (u64, u64),
+    // Bait-Prey pairs with ambiguous flanks
+    n_ambiguous: (u64, u64),
     n_prey_nv_pair: (u64, u64),
     n_bait_nv_pair: (u64, u64),
     n_nv_pair: (u64, u64),
     n_bait_bait: (u64, u64),
     n_prey_prey: (u64, u64),
+    // oriented pairs which bait and prey are on the opposite side of design
+    n_reversed: (u64, u64),
 }
 
 impl<'a> ResCounter {
     fn new() -> Self {
         Self {
             n_valid_pair: (0, 0),
+            n_ambiguous: (0, 0),
             n_prey_nv_pair: (0, 0),
             n_bait_nv_pair: (0, 0),
             n_nv_pair: (0, 0),
             n_bait_bait: (0, 0),
             n_prey_prey: (0, 0),
+            n_reversed: (0, 0),
         }
     }
 
+    /// Count a pair, if `oriented`, node1 should be on the bait side of design.
+    /// The oriented Bait-Prey and Prey-Bait pairs are counted as edges from the bait side
+    /// to the prey side, the latter are flagged as reversed in output.
+    /// Pairs of genes of the same role are not edges.
     fn count(&mut self,
-             bait_prey_cnt: &mut HashMap<(&'a String, &'a String), u64>,
-             node1: &'a Node, node2: &'a Node, cnt: u64) {
+             bait_prey_cnt: &mut PairCnt<'a>,
+             node1: &'a Node, node2: &'a Node, cnt: u64, oriented: bool) {
         match (node1, node2) {
-            (Node::Prey(p_name), Node::Bait(b_name)) | (Node::Bait(b_name), Node::Prey(p_name)) => {
-                *bait_prey_cnt.entry((b_name, p_name)).or_insert(0) += cnt;
+            (Node::Prey(preys), Node::Bait(baits)) if oriented => {
+                *bait_prey_cnt.entry((preys, baits)).or_insert(0) += cnt;
+                self.n_reversed.0 += 1;
+                self.n_reversed.1 += cnt;
+            },
+            (Node::Prey(preys), Node::Bait(baits)) | (Node::Bait(baits), Node::Prey(preys)) => {
+                *bait_prey_cnt.entry((baits, preys)).or_insert(0) += cnt;
                 self.n_valid_pair.0 += 1;
                 self.n_valid_pair.1 += cnt;
+                if baits.len() > 1 || preys.len() > 1 {
+                    self.n_ambiguous.0 += 1;
+                    self.n_ambiguous.1 += cnt;
+                }
             },
             (Node::Prey(_), Node::NotValid(_)) | (Node::NotValid(_), Node::Prey(_)) => {
                 self.n_prey_nv_pair.0 += 1;
@@ -173,17 +309,21 @@ impl fmt::Display for ResCounter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let total_pairs = self.n_valid_pair.0 + self.n_bait_nv_pair.0 +
                           self.n_prey_nv_pair.0 + self.n_nv_pair.0 +
-                          self.n_bait_bait.0 + self.n_bait_bait.1;
+                          self.n_bait_bait.0 + self.n_prey_prey.0 +
+                          self.n_reversed.0;
         let total_reads = self.n_valid_pair.1 + self.n_bait_nv_pair.1 +
                           self.n_prey_nv_pair.1 + self.n_nv_pair.1 +
-                          self.n_bait_bait.1 + self.n_prey_prey.1;
+                          self.n_bait_bait.1 + self.n_prey_prey.1 +
+                          self.n_reversed.1;
         let ratio = |c, total| {
-            if total == 0 { return format!("0%"); }
+            if total == 0 { return "0%".to_string(); }
             format!("{:.2}%", ((c*100) as f64) / (total as f64))
         };
         write!(f,
             "Count result:
     Bait-Prey\t{}\t{}\t{}\t{}
+      ambiguous\t{}\t{}\t{}\t{}
+    Prey-Bait(reversed)\t{}\t{}\t{}\t{}
     Bait-Bait\t{}\t{}\t{}\t{}
     Prey-Prey\t{}\t{}\t{}\t{}
     Bait-NotValid\t{}\t{}\t{}\t{}
@@ -193,6 +333,10 @@ total pairs: {}
 total reads: {}\n",
             self.n_valid_pair.0, ratio(self.n_valid_pair.0, total_pairs),
             self.n_valid_pair.1, ratio(self.n_valid_pair.1, total_reads),
+            self.n_ambiguous.0, ratio(self.n_ambiguous.0, total_pairs),
+            self.n_ambiguous.1, ratio(self.n_ambiguous.1, total_reads),
+            self.n_reversed.0, ratio(self.n_reversed.0, total_pairs),
+            self.n_reversed.1, ratio(self.n_reversed.1, total_reads),
             self.n_bait_bait.0, ratio(self.n_bait_bait.0, total_pairs),
             self.n_bait_bait.1, ratio(self.n_bait_bait.1, total_reads),
             self.n_prey_prey.0, ratio(self.n_prey_prey.0, total_pairs),
@@ -218,7 +362,16 @@ fn main() {
              .help("Pair count file(.cnt)."))
         .arg(Arg::with_name("sam")
              .required(true)
-             .help("Aligned sam file"))
+             .help("Aligned SAM, gzipped SAM or BAM file ('-' for stdin), or the sequence file(.cnt.fq) when mapping with --library."))
+        .arg(Arg::with_name("library")
+             .short("l")
+             .long("library")
+             .takes_value(true)
+             .help("Library fasta file, map the sequences with the built-in mapper instead of reading sam file."))
+        .arg(Arg::with_name("map_detail")
+             .long("map_detail")
+             .takes_value(true)
+             .help("Path to output all hits of the built-in mapper."))
         .arg(Arg::with_name("output")
              .short("o")
              .long("output")
@@ -230,6 +383,11 @@ fn main() {
              .long("detail")
              .takes_value(true)
              .help("Path to parse detail."))
+        .arg(Arg::with_name("design")
+             .long("design")
+             .takes_value(true)
+             .possible_values(&["bait_prey", "prey_bait"])
+             .help("Which side of linker the bait and prey are on, used for oriented pair count file."))
         .arg(Arg::with_name("th_mapq")
              .long("th_mapq")
              .takes_value(true)
@@ -237,64 +395,259 @@ fn main() {
         .arg(Arg::with_name("th_mismatch")
              .long("th_mismatch")
              .takes_value(true)
-             .help("Threshold of number of mismatches."))
+             .help("Threshold of number of mismatches, also the mismatches allowed by the built-in mapper."))
         .arg(Arg::with_name("th_aligned")
              .long("th_aligned")
              .takes_value(true)
-             .help("Threshold of number of alignments."))
+             .help("Threshold of number of candidate genes aligned, from the primary alignment, XA, SA and secondary alignments."))
+        .arg(Arg::with_name("multi_hit")
+             .long("multi_hit")
+             .takes_value(true)
+             .possible_values(&["discard", "group", "em"])
+             .help("How to handle flanks aligned to more genes than --th_aligned: discard, group (count edges of gene groups like bait_A|bait_B), or em (distribute to genes by the unique flanks)."))
+        .arg(Arg::with_name("max_candidates")
+             .long("max_candidates")
+             .takes_value(true)
+             .help("Max number of candidate genes of the flanks kept in group or em mode."))
+        .arg(Arg::with_name("annotation")
+             .long("annotation")
+             .takes_value(true)
+             .help("Annotation table of library genes in TSV format: gene ID, role (bait or prey), symbol and description (optional). The symbols are added to the output."))
+        .arg(Arg::with_name("bait_pattern")
+             .long("bait_pattern")
+             .takes_value(true)
+             .help("Regex of bait gene names, for the genes not in annotation table."))
+        .arg(Arg::with_name("prey_pattern")
+             .long("prey_pattern")
+             .takes_value(true)
+             .help("Regex of prey gene names, for the genes not in annotation table."))
         .get_matches();
 
     let path_cnt = matches.value_of("cnt").unwrap();
     let path_sam = matches.value_of("sam").unwrap();
     let path_out = matches.value_of("output").unwrap();
-    let th_mapq: u8 = matches.value_of("th_mapq").unwrap_or("0").parse().unwrap();
-    let th_mismatch: u8 = matches.value_of("th_mismatch").unwrap_or("0").parse().unwrap();
-    let th_aligned: u8 = matches.value_of("th_aligned").unwrap_or("1").parse().unwrap();
-    let mut detail_file = match matches.value_of("detail") {
-        Some(p) => Some(File::create(p).unwrap()),
-        None => None,
+    let mut roles = match Roles::new(
+            matches.value_of("bait_pattern").unwrap_or("^bait_"),
+            matches.value_of("prey_pattern").unwrap_or("^prey_")) {
+        Ok(roles) => roles,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1)
+        },
     };
+    let path_annotation = matches.value_of("annotation");
+    if let Some(path) = path_annotation {
+        roles.load_table(path);
+        info!("Load roles of {} genes from annotation: {}", roles.n_annotated(), path);
+    }
+    let params = NodeParams {
+        th_mapq: matches.value_of("th_mapq").unwrap_or("0").parse().unwrap(),
+        th_mismatch: matches.value_of("th_mismatch").unwrap_or("0").parse().unwrap(),
+        th_aligned: matches.value_of("th_aligned").unwrap_or("1").parse().unwrap(),
+        multi_hit: MultiHit::from_str(matches.value_of("multi_hit").unwrap_or("discard")),
+        max_candidates: matches.value_of("max_candidates").unwrap_or("5").parse().unwrap(),
+        roles,
+    };
+    let prey_first = matches.value_of("design") == Some("prey_bait");
+    let mut detail_file = matches.value_of("detail").map(|p| File::create(p).unwrap());
 
-    let key2name = load_sam(path_sam, th_mapq, th_mismatch, th_aligned);
+    let key2name = match matches.value_of("library") {
+        Some(path_lib) => load_mapped(path_sam, path_lib, &params, matches.value_of("map_detail")),
+        None => match load_sam(path_sam, &params) {
+            Ok(key2node) => key2node,
+            Err(e) => {
+                error!("Failed to read SAM file: {}", e);
+                process::exit(1)
+            },
+        },
+    };
+    let mut unknown: Vec<&String> = key2name.values().filter_map(|n| match n {
+        Node::NotValid(NotValidType::UnknownRole(gene)) => Some(gene),
+        _ => None,
+    }).collect();
+    unknown.sort();
+    unknown.dedup();
+    if !unknown.is_empty() {
+        let names: Vec<&str> = unknown.iter().take(5).map(|g| g.as_str()).collect();
+        warn!("{} references are neither bait nor prey, e.g. {}. They are reported as UnknownRole.",
+            unknown.len(), names.join(", "));
+    }
     let cnt_file = BufReader::new(File::open(path_cnt).unwrap());
 
-    let mut bait_prey_cnt:HashMap<(&String, &String), u64> =  HashMap::new();
+    let mut bait_prey_cnt: PairCnt = HashMap::new();
     let mut res_counter = ResCounter::new();
+    let (mut n_oriented, mut n_canonical) = (0, 0);
 
-    for line in cnt_file.lines() {
+    // aligners may omit the unmapped queries from output
+    let not_found = Node::NotValid(NotValidType::NotFound);
+    for (i, line) in cnt_file.lines().enumerate() {
         let line = line.unwrap();
-        let items: Vec<&str> = line.trim().split("\t").collect();
-        let key1: u64 = items[0].parse().unwrap();
-        let key2: u64 = items[1].parse().unwrap();
-        let cnt: u64 = items[2].parse().unwrap();
-        let node1 = &key2name[&key1];
-        let node2 = &key2name[&key2];
+        let items: Vec<&str> = line.trim().split('\t').collect();
+        let cnt: u64 = match items.get(2).map(|c| c.parse()) {
+            Some(Ok(c)) => c,
+            _ => {
+                error!("Failed to read pair count file: {}:{}: invalid line: {}", path_cnt, i + 1, line);
+                process::exit(1)
+            },
+        };
+        let key1 = items[0];
+        let key2 = items[1];
+        // the oriented pair count file has a strand column, `.` for the canonical keys
+        let strand = items.get(3).filter(|s| **s != ".");
+        if strand.is_some() { n_oriented += 1 } else { n_canonical += 1 }
+        let node1 = key2name.get(key1).unwrap_or(&not_found);
+        let node2 = key2name.get(key2).unwrap_or(&not_found);
 
-        res_counter.count(&mut bait_prey_cnt, node1, node2, cnt);
+        if strand.is_some() && prey_first {
+            res_counter.count(&mut bait_prey_cnt, node2, node1, cnt, true);
+        } else {
+            res_counter.count(&mut bait_prey_cnt, node1, node2, cnt, strand.is_some());
+        }
 
         if let Some(mut f) = detail_file {
             // write detail
-            let _ = writeln!(f,
-                "{}\t{}\t{}\t{}\t{}",
-                key1, key2, cnt,
-                node1, node2,
-            );
+            let _ = match strand {
+                Some(strand) => {
+                    let (bait_side, prey_side) = if prey_first { (node2, node1) } else { (node1, node2) };
+                    writeln!(f,
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        key1, key2, cnt,
+                        node1, node2,
+                        strand, orientation(bait_side.role(), prey_side.role()),
+                    )
+                },
+                None => writeln!(f,
+                    "{}\t{}\t{}\t{}\t{}",
+                    key1, key2, cnt,
+                    node1, node2,
+                ),
+            };
             detail_file = Some(f);
         }
     }
 
+    let oriented = n_oriented > 0;
+    if oriented && n_canonical > 0 {
+        warn!("{} of {} pairs have no strand, they are counted without orientation.",
+            n_canonical, n_oriented + n_canonical);
+    }
+    if !oriented && matches.is_present("design") {
+        warn!("The pair count file has no strand column (paircnt without --oriented), --design is ignored.");
+    }
     info!("{}", res_counter);
 
-    let mut bait_prey_vec = vec![];
-    for ((bait, prey), cnt) in bait_prey_cnt.iter() {
-        bait_prey_vec.push(((bait, prey), cnt));
-    }
-    bait_prey_vec.sort_by(|a, b| b.1.cmp(&a.1));
+    let edges = match params.multi_hit {
+        MultiHit::Em => em_edges(&bait_prey_cnt),
+        _ => group_edges(&bait_prey_cnt),
+    };
+    let mut bait_prey_vec: Vec<_> = edges.iter().collect();
+    bait_prey_vec.sort_by(|a, b| b.1.0.partial_cmp(&a.1.0).unwrap());
 
-    let mut file_out = File::create(path_out.clone()).unwrap();
+    let mut file_out = File::create(path_out).unwrap();
     info!("Output counted Bait-Prey pairs to: {}", path_out);
-    for ((bait, prey), cnt) in bait_prey_vec {
-        let _ = write!(file_out, "{}\t{}\t{}\n", bait, prey, cnt);
+    // symbols of genes (or groups) in annotation
+    let symbols = |genes: &str| -> String {
+        genes.split('|').map(|g| params.roles.symbol(g)).collect::<Vec<&str>>().join("|")
+    };
+    // genes of a group have the same role
+    let role = |genes: &str| params.roles.role(genes.split('|').next().unwrap());
+    for ((bait, prey), (cnt, ambiguous)) in bait_prey_vec {
+        // flag the edges with ambiguous flanks
+        let flag = if *ambiguous { "ambiguous" } else { "unique" };
+        let mut line = match params.multi_hit {
+            MultiHit::Discard => format!("{}\t{}\t{}", bait, prey, cnt),
+            MultiHit::Group => format!("{}\t{}\t{}\t{}", bait, prey, cnt, flag),
+            MultiHit::Em => format!("{}\t{}\t{:.2}\t{}", bait, prey, cnt, flag),
+        };
+        if oriented {
+            line.push_str(&format!("\t{}", orientation(role(bait), role(prey))));
+        }
+        if path_annotation.is_some() {
+            line.push_str(&format!("\t{}\t{}", symbols(bait), symbols(prey)));
+        }
+        let _ = writeln!(file_out, "{}", line);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(multi_hit: MultiHit) -> NodeParams {
+        let mut roles = Roles::new("^bait_", "^prey_").unwrap();
+        roles.read_table("ORF001\tprey\n".as_bytes());
+        NodeParams { th_mapq: 0, th_mismatch: 0, th_aligned: 1, multi_hit, max_candidates: 5, roles }
+    }
+
+    fn rec(genes: &[&str]) -> SamRec {
+        SamRec {
+            rname: genes[0].to_string(), mapq: 0, n_mismatch: 0,
+            genes: genes.iter().map(|g| g.to_string()).collect(),
+        }
     }
 
+    #[test]
+    fn test_node_roles_of_candidates() {
+        let params = params(MultiHit::Group);
+        assert_eq!(to_node(&rec(&["bait_B", "bait_A"]), &params).to_string(), "Bait:bait_A|bait_B");
+        // the annotated role is used for the candidates
+        assert_eq!(to_node(&rec(&["prey_X", "ORF001"]), &params).to_string(), "Prey:ORF001|prey_X");
+        // candidates of both roles are rejected
+        assert_eq!(to_node(&rec(&["bait_A", "ORF001"]), &params).to_string(), "TooManyAligned:2:bait_A,ORF001");
+        assert_eq!(to_node(&rec(&["bait_A", "gene_Z"]), &params).to_string(), "UnknownRole:gene_Z");
+    }
+
+    fn node(role: Role, genes: &[&str]) -> Node {
+        let genes = genes.iter().map(|g| g.to_string()).collect();
+        match role {
+            Role::Bait => Node::Bait(genes),
+            Role::Prey => Node::Prey(genes),
+        }
+    }
+
+    #[test]
+    fn test_count_oriented_pairs() {
+        let (bait_a, bait_b) = (node(Role::Bait, &["bait_A"]), node(Role::Bait, &["bait_B"]));
+        let (prey_x, prey_y) = (node(Role::Prey, &["prey_X"]), node(Role::Prey, &["prey_Y"]));
+        let not_found = Node::NotValid(NotValidType::NotFound);
+        let mut counter = ResCounter::new();
+        let mut pairs = PairCnt::new();
+        counter.count(&mut pairs, &bait_a, &prey_x, 5, true);
+        counter.count(&mut pairs, &prey_y, &bait_b, 3, true);
+        counter.count(&mut pairs, &bait_a, &bait_b, 7, true);
+        counter.count(&mut pairs, &prey_x, &prey_y, 2, true);
+        counter.count(&mut pairs, &bait_a, &not_found, 1, true);
+        // the reversed pair is an edge from the bait side, same-role pairs are not edges
+        let edges = group_edges(&pairs);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[&("bait_A".to_string(), "prey_X".to_string())], (5.0, false));
+        assert_eq!(edges[&("prey_Y".to_string(), "bait_B".to_string())], (3.0, false));
+        assert_eq!((counter.n_valid_pair, counter.n_reversed), ((1, 5), (1, 3)));
+        assert_eq!((counter.n_bait_bait, counter.n_prey_prey), ((1, 7), (1, 2)));
+    }
+
+    #[test]
+    fn test_count_canonical_pairs() {
+        let (bait_a, prey_x) = (node(Role::Bait, &["bait_A"]), node(Role::Prey, &["prey_X"]));
+        let mut counter = ResCounter::new();
+        let mut pairs = PairCnt::new();
+        counter.count(&mut pairs, &prey_x, &bait_a, 3, false);
+        counter.count(&mut pairs, &bait_a, &bait_a, 2, false);
+        let edges = group_edges(&pairs);
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[&("bait_A".to_string(), "prey_X".to_string())], (3.0, false));
+        assert_eq!((counter.n_valid_pair, counter.n_reversed), ((1, 3), (0, 0)));
+    }
+
+    #[test]
+    fn test_orientation() {
+        let (bait, prey) = (node(Role::Bait, &["bait_A"]), node(Role::Prey, &["prey_X"]));
+        let not_found = Node::NotValid(NotValidType::NotFound);
+        assert_eq!(orientation(bait.role(), prey.role()), "concordant");
+        assert_eq!(orientation(prey.role(), bait.role()), "reversed");
+        assert_eq!(orientation(bait.role(), bait.role()), "discordant");
+        assert_eq!(orientation(prey.role(), prey.role()), "discordant");
+        assert_eq!(orientation(bait.role(), not_found.role()), "not_valid");
+    }
 }
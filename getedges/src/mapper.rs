@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+
+
+const MAX_SEED_LEN: usize = 32;
+// shorter seeds hit too many positions of the library
+const MIN_SEED_LEN: usize = 8;
+
+
+fn base_code(b: u8) -> Option<u64> {
+    match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+
+fn complement(b: u8) -> u8 {
+    match b {
+        b'A' | b'a' => b'T',
+        b'C' | b'c' => b'G',
+        b'G' | b'g' => b'C',
+        b'T' | b't' => b'A',
+        _ => b'N',
+    }
+}
+
+
+pub fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|b| complement(*b)).collect()
+}
+
+
+fn pack_seed(seq: &[u8]) -> Option<u64> {
+    let mut code: u64 = 0;
+    for b in seq {
+        code = (code << 2) | base_code(*b)?;
+    }
+    Some(code)
+}
+
+
+pub struct Reference {
+    pub name: String,
+    pub seq: Vec<u8>,
+}
+
+
+pub fn load_fasta(path: &str) -> Vec<Reference> {
+    let mut refs: Vec<Reference> = vec![];
+    let f = BufReader::new(File::open(path).unwrap());
+    for line in f.lines() {
+        let line = line.unwrap();
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('>') {
+            let name = header.split_whitespace().next().unwrap_or("").to_string();
+            refs.push(Reference { name, seq: vec![] });
+        } else if let Some(r) = refs.last_mut() {
+            r.seq.extend(line.bytes().map(|b| b.to_ascii_uppercase()));
+        }
+    }
+    refs
+}
+
+
+/// Read (id, sequence) records from fastq file, like the `.cnt.fq` produced by paircnt.
+pub fn load_fastq(path: &str) -> Vec<(String, Vec<u8>)> {
+    let mut recs = vec![];
+    let f = BufReader::new(File::open(path).unwrap());
+    let mut lines = f.lines();
+    while let Some(header) = lines.next() {
+        let header = header.unwrap();
+        if header.is_empty() { continue }
+        let seq = lines.next().unwrap().unwrap();
+        let _ = lines.next();  // '+' line
+        let _ = lines.next();  // quality line
+        let id = header[1..].split_whitespace().next().unwrap_or("").to_string();
+        recs.push((id, seq.into_bytes()));
+    }
+    recs
+}
+
+
+pub struct Hit {
+    pub ref_idx: usize,
+    // 0-based position of the leftmost base on reference
+    pub pos: usize,
+    pub reverse: bool,
+    pub n_mismatch: usize,
+}
+
+
+/// Mapping result of a query, only hits with fewest mismatches are kept.
+pub struct MapRes {
+    pub hits: Vec<Hit>,
+}
+
+impl MapRes {
+    pub fn n_mismatch(&self) -> Option<usize> {
+        self.hits.first().map(|h| h.n_mismatch)
+    }
+}
+
+
+/// Seed index of the library, for mapping short sequences with few mismatches.
+/// Query is split into `max_mismatch + 1` non-overlapping seeds, so at least one
+/// seed matches exactly (pigeonhole principle), then candidate positions are
+/// verified by hamming distance on both strands.
+/// Seeds are at least `MIN_SEED_LEN` bases, queries shorter than `min_query_len()`
+/// can't be split into enough seeds and are left unmapped.
+pub struct Mapper {
+    pub refs: Vec<Reference>,
+    seed_len: usize,
+    max_mismatch: usize,
+    index: HashMap<u64, Vec<(u32, u32)>>,
+}
+
+impl Mapper {
+    pub fn new(refs: Vec<Reference>, min_query_len: usize, max_mismatch: usize) -> Self {
+        let seed_len = (min_query_len / (max_mismatch + 1)).clamp(MIN_SEED_LEN, MAX_SEED_LEN);
+        let mut index: HashMap<u64, Vec<(u32, u32)>> = HashMap::new();
+        for (i, r) in refs.iter().enumerate() {
+            if r.seq.len() < seed_len { continue }
+            for pos in 0..=(r.seq.len() - seed_len) {
+                if let Some(code) = pack_seed(&r.seq[pos..pos+seed_len]) {
+                    index.entry(code).or_default().push((i as u32, pos as u32));
+                }
+            }
+        }
+        Self { refs, seed_len, max_mismatch, index }
+    }
+
+    /// Shortest query can be mapped with `max_mismatch` mismatches.
+    pub fn min_query_len(&self) -> usize {
+        self.seed_len * (self.max_mismatch + 1)
+    }
+
+    fn search_strand(&self, query: &[u8], reverse: bool, hits: &mut Vec<Hit>) {
+        let n_seeds = self.max_mismatch + 1;
+        let step = query.len() / n_seeds;
+        let mut candidates: HashSet<(u32, usize)> = HashSet::new();
+        for s in 0..n_seeds {
+            let offset = s * step;
+            let code = match pack_seed(&query[offset..offset+self.seed_len]) {
+                Some(c) => c,
+                None => continue,
+            };
+            if let Some(positions) = self.index.get(&code) {
+                for (ref_idx, pos) in positions {
+                    if (*pos as usize) < offset { continue }
+                    candidates.insert((*ref_idx, *pos as usize - offset));
+                }
+            }
+        }
+        for (ref_idx, start) in candidates {
+            let r = &self.refs[ref_idx as usize].seq;
+            if start + query.len() > r.len() { continue }
+            let n_mismatch = query.iter().zip(&r[start..start+query.len()])
+                .filter(|(a, b)| base_code(**a).is_none() || a != b).count();
+            if n_mismatch <= self.max_mismatch {
+                hits.push(Hit { ref_idx: ref_idx as usize, pos: start, reverse, n_mismatch });
+            }
+        }
+    }
+
+    /// Distinct reference names of hits, a reference (gene) may have
+    /// multiple entries, such as the flanks produced by in-silico digestion.
+    pub fn hit_names(&self, res: &MapRes) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        for h in &res.hits {
+            let name = self.refs[h.ref_idx].name.as_str();
+            if !names.contains(&name) { names.push(name) }
+        }
+        names
+    }
+
+    pub fn map(&self, query: &[u8]) -> MapRes {
+        let mut hits = vec![];
+        if query.len() >= self.min_query_len() {
+            let query: Vec<u8> = query.iter().map(|b| b.to_ascii_uppercase()).collect();
+            self.search_strand(&query, false, &mut hits);
+            self.search_strand(&revcomp(&query), true, &mut hits);
+        }
+        let best = hits.iter().map(|h| h.n_mismatch).min();
+        hits.retain(|h| Some(h.n_mismatch) == best);
+        hits.sort_by_key(|h| (h.ref_idx, h.pos, h.reverse));
+        MapRes { hits }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs() -> Vec<Reference> {
+        vec![
+            Reference { name: "bait_A".to_string(), seq: b"ACGGTCAATGCTTAGCCATGGACTTCAGGTAC".to_vec() },
+            Reference { name: "prey_X".to_string(), seq: b"TTGACCGATAGGCATCCAGTTAGCACGATCGA".to_vec() },
+            // shares the last 17 bases with bait_A
+            Reference { name: "bait_B".to_string(), seq: b"GATTACACTCGCGAATCCATGGACTTCAGGTAC".to_vec() },
+        ]
+    }
+
+    fn hits(res: &MapRes) -> Vec<(usize, usize, bool, usize)> {
+        res.hits.iter().map(|h| (h.ref_idx, h.pos, h.reverse, h.n_mismatch)).collect()
+    }
+
+    #[test]
+    fn test_map_exact() {
+        let mapper = Mapper::new(refs(), 13, 0);
+        let res = mapper.map(b"CAATGCTTAGCCA");
+        assert_eq!(hits(&res), vec![(0, 5, false, 0)]);
+        assert_eq!(mapper.hit_names(&res), vec!["bait_A"]);
+        // lowercase query
+        assert_eq!(hits(&mapper.map(b"gataggcatccag")), vec![(1, 6, false, 0)]);
+        // no hit with a mismatch
+        assert!(mapper.map(b"CAATGCTAAGCCA").hits.is_empty());
+        assert_eq!(mapper.map(b"CAATGCTAAGCCA").n_mismatch(), None);
+    }
+
+    #[test]
+    fn test_map_mismatch() {
+        let mapper = Mapper::new(refs(), 24, 2);
+        // one mismatch in each of the first two seeds
+        let res = mapper.map(b"CTATGCTTAGCGATGGACTTCAGG");
+        assert_eq!(hits(&res), vec![(0, 5, false, 2)]);
+        assert_eq!(res.n_mismatch(), Some(2));
+        // N counts as a mismatch
+        assert_eq!(hits(&mapper.map(b"CAATGCNTAGCCATGGACTTCAGG")), vec![(0, 5, false, 1)]);
+        // beyond --th_mismatch
+        assert!(mapper.map(b"CTATGCTTAGCGATGGACATCAGG").hits.is_empty());
+        // hits with more mismatches than the best are dropped
+        let mut rs = refs();
+        rs.push(Reference { name: "prey_Y".to_string(), seq: b"CAATGCTAAGCCATGGACTTCAGG".to_vec() });
+        let mapper = Mapper::new(rs, 24, 2);
+        assert_eq!(hits(&mapper.map(b"CAATGCTAAGCCATGGACTTCAGG")), vec![(3, 0, false, 0)]);
+    }
+
+    #[test]
+    fn test_map_multi_gene() {
+        let mapper = Mapper::new(refs(), 16, 1);
+        let res = mapper.map(b"CCATGGACTTCAGGTA");
+        assert_eq!(hits(&res), vec![(0, 15, false, 0), (2, 16, false, 0)]);
+        assert_eq!(mapper.hit_names(&res), vec!["bait_A", "bait_B"]);
+    }
+
+    #[test]
+    fn test_map_reverse() {
+        let mapper = Mapper::new(refs(), 16, 1);
+        let query = revcomp(b"GATAGGCATCCAGTTA");
+        assert_eq!(query, b"TAACTGGATGCCTATC".to_vec());
+        let res = mapper.map(&query);
+        assert_eq!(hits(&res), vec![(1, 6, true, 0)]);
+        assert_eq!(mapper.hit_names(&res), vec!["prey_X"]);
+    }
+
+    #[test]
+    fn test_min_seed_len() {
+        // seeds of a short query are not shorter than MIN_SEED_LEN
+        let mapper = Mapper::new(refs(), 5, 1);
+        assert_eq!(mapper.seed_len, MIN_SEED_LEN);
+        assert_eq!(mapper.min_query_len(), 16);
+        assert!(mapper.map(b"CCATGGACTTCAG").hits.is_empty());
+        assert_eq!(hits(&mapper.map(b"CCATGGACTTCAGGTA")), vec![(0, 15, false, 0), (2, 16, false, 0)]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+
+const EM_MAX_ITER: usize = 1000;
+const EM_TOL: f64 = 1e-6;
+// edges with less estimated count are dropped, they are 0.00 in output
+const EM_MIN_COUNT: f64 = 0.005;
+
+
+/// How to handle the flanks aligned to multiple genes of the same role.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MultiHit {
+    // report as TooManyAligned
+    Discard,
+    // assign to the gene group, like `bait_A|bait_B`
+    Group,
+    // distribute to candidates by their abundance estimated from the unique flanks
+    Em,
+}
+
+impl MultiHit {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "discard" => MultiHit::Discard,
+            "group" => MultiHit::Group,
+            "em" => MultiHit::Em,
+            _ => panic!("Unknown multi-hit mode: {}", s),
+        }
+    }
+}
+
+
+// (bait candidates, prey candidates) -> count
+pub type PairCnt<'a> = HashMap<(&'a [String], &'a [String]), u64>;
+
+// (bait, prey) -> (count, whether any of the count comes from ambiguous flanks)
+pub type Edges = HashMap<(String, String), (f64, bool)>;
+
+
+/// Edges at group level, the candidates are joined by `|`.
+pub fn group_edges(pairs: &PairCnt) -> Edges {
+    let mut edges = Edges::new();
+    for ((baits, preys), cnt) in pairs {
+        let ambiguous = baits.len() > 1 || preys.len() > 1;
+        let e = edges.entry((baits.join("|"), preys.join("|"))).or_insert((0.0, false));
+        e.0 += *cnt as f64;
+        e.1 |= ambiguous;
+    }
+    edges
+}
+
+
+/// Fraction of a flank assigned to each candidate, uniform if no evidence.
+fn weights<'a>(genes: &'a [String], abundance: &HashMap<&str, f64>) -> Vec<(&'a str, f64)> {
+    let total: f64 = genes.iter().map(|g| abundance[g.as_str()]).sum();
+    genes.iter().map(|g| {
+        let w = if total > 0.0 { abundance[g.as_str()] / total } else { 1.0 / genes.len() as f64 };
+        (g.as_str(), w)
+    }).collect()
+}
+
+
+/// Distribute the ambiguous flanks to candidates by EM. Abundance of gene starts from the
+/// count of its unique flanks, and is updated with the shares of ambiguous flanks.
+pub fn em_edges(pairs: &PairCnt) -> Edges {
+    let mut unique: HashMap<&str, f64> = HashMap::new();
+    for ((baits, preys), cnt) in pairs {
+        for side in [baits, preys].iter() {
+            if side.len() == 1 {
+                *unique.entry(side[0].as_str()).or_insert(0.0) += *cnt as f64;
+            } else {
+                for g in side.iter() { unique.entry(g.as_str()).or_insert(0.0); }
+            }
+        }
+    }
+
+    let mut abundance = unique.clone();
+    for _ in 0..EM_MAX_ITER {
+        let mut updated = unique.clone();
+        for ((baits, preys), cnt) in pairs {
+            for side in [baits, preys].iter().filter(|s| s.len() > 1) {
+                for (g, w) in weights(side, &abundance) {
+                    *updated.get_mut(g).unwrap() += *cnt as f64 * w;
+                }
+            }
+        }
+        let diff = updated.iter().map(|(g, a)| (a - abundance[g]).abs()).fold(0.0, f64::max);
+        abundance = updated;
+        if diff < EM_TOL { break }
+    }
+
+    let mut edges = Edges::new();
+    for ((baits, preys), cnt) in pairs {
+        let ambiguous = baits.len() > 1 || preys.len() > 1;
+        for (b, wb) in weights(baits, &abundance) {
+            for (p, wp) in weights(preys, &abundance) {
+                let e = edges.entry((b.to_string(), p.to_string())).or_insert((0.0, false));
+                e.0 += *cnt as f64 * wb * wp;
+                e.1 |= ambiguous;
+            }
+        }
+    }
+    edges.retain(|_, e| e.0 >= EM_MIN_COUNT);
+    edges
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genes(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn edge(edges: &Edges, bait: &str, prey: &str) -> (f64, bool) {
+        edges[&(bait.to_string(), prey.to_string())]
+    }
+
+    #[test]
+    fn test_em_by_abundance() {
+        let (a, b, ab) = (genes(&["bait_A"]), genes(&["bait_B"]), genes(&["bait_A", "bait_B"]));
+        let (x, y) = (genes(&["prey_X"]), genes(&["prey_Y"]));
+        let mut pairs = PairCnt::new();
+        pairs.insert((&a, &x), 30);
+        pairs.insert((&b, &y), 10);
+        pairs.insert((&ab, &x), 10);
+        let edges = em_edges(&pairs);
+        // abundance converges to A 37.5 and B 12.5, the ambiguous flanks are split 3:1
+        let (ax, bx) = (edge(&edges, "bait_A", "prey_X"), edge(&edges, "bait_B", "prey_X"));
+        assert!((ax.0 - 37.5).abs() < 1e-3 && ax.1);
+        assert!((bx.0 - 2.5).abs() < 1e-3 && bx.1);
+        assert_eq!(edge(&edges, "bait_B", "prey_Y"), (10.0, false));
+        let total: f64 = edges.values().map(|e| e.0).sum();
+        assert!((total - 50.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_em_without_evidence() {
+        // no unique flanks of the candidates, split evenly
+        let (ab, x) = (genes(&["bait_A", "bait_B"]), genes(&["prey_X"]));
+        let mut pairs = PairCnt::new();
+        pairs.insert((&ab, &x), 8);
+        let edges = em_edges(&pairs);
+        assert_eq!(edge(&edges, "bait_A", "prey_X"), (4.0, true));
+        assert_eq!(edge(&edges, "bait_B", "prey_X"), (4.0, true));
+    }
+
+    #[test]
+    fn test_em_drops_tiny_edges() {
+        let (a, ab, x) = (genes(&["bait_A"]), genes(&["bait_A", "bait_B"]), genes(&["prey_X"]));
+        let mut pairs = PairCnt::new();
+        pairs.insert((&a, &x), 100000);
+        pairs.insert((&ab, &x), 1);
+        let edges = em_edges(&pairs);
+        assert_eq!(edges.len(), 1);
+        assert!(!edges.contains_key(&("bait_B".to_string(), "prey_X".to_string())));
+    }
+
+    #[test]
+    fn test_group_edges() {
+        let (ab, x) = (genes(&["bait_A", "bait_B"]), genes(&["prey_X"]));
+        let a = genes(&["bait_A"]);
+        let mut pairs = PairCnt::new();
+        pairs.insert((&ab, &x), 3);
+        pairs.insert((&a, &x), 2);
+        let edges = group_edges(&pairs);
+        assert_eq!(edge(&edges, "bait_A|bait_B", "prey_X"), (3.0, true));
+        assert_eq!(edge(&edges, "bait_A", "prey_X"), (2.0, false));
+    }
+}
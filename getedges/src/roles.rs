@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+
+use regex::Regex;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Bait,
+    Prey,
+}
+
+impl Role {
+    fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "bait" => Some(Role::Bait),
+            "prey" => Some(Role::Prey),
+            _ => None,
+        }
+    }
+}
+
+
+/// Roles of the library genes, from the annotation table, or by matching
+/// the gene names with the patterns of bait and prey.
+pub struct Roles {
+    // gene ID -> (role, symbol)
+    table: HashMap<String, (Role, Option<String>)>,
+    bait_pattern: Regex,
+    prey_pattern: Regex,
+}
+
+impl Roles {
+    pub fn new(bait_pattern: &str, prey_pattern: &str) -> Result<Self, String> {
+        let regex = |p: &str| Regex::new(p).map_err(|e| format!("Invalid pattern {}: {}", p, e));
+        Ok(Self { table: HashMap::new(), bait_pattern: regex(bait_pattern)?, prey_pattern: regex(prey_pattern)? })
+    }
+
+    /// Load the annotation table, the columns are gene ID, role (bait or prey),
+    /// and optionally the gene symbol and description (not used).
+    /// Lines start with `#` and the header line (with role column `role`) are skipped.
+    pub fn load_table(&mut self, path: &str) {
+        self.read_table(BufReader::new(File::open(path).unwrap()));
+    }
+
+    /// Load the annotation table from reader, as `load_table`.
+    pub fn read_table<R: BufRead>(&mut self, reader: R) {
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.unwrap();
+            if line.trim().is_empty() || line.starts_with('#') { continue }
+            let items: Vec<&str> = line.split('\t').map(|s| s.trim()).collect();
+            if items.len() < 2 {
+                panic!("Annotation line {}: expect at least 2 columns (gene, role), got: {}", i + 1, line);
+            }
+            if items[1].eq_ignore_ascii_case("role") { continue }
+            let role = Role::from_str(items[1])
+                .unwrap_or_else(|| panic!("Annotation line {}: role should be bait or prey, got: {}", i + 1, items[1]));
+            let symbol = items.get(2).filter(|s| !s.is_empty()).map(|s| s.to_string());
+            self.table.insert(items[0].to_string(), (role, symbol));
+        }
+    }
+
+    pub fn n_annotated(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Role of gene, the annotation table first, then the name patterns.
+    pub fn role(&self, gene: &str) -> Option<Role> {
+        if let Some((role, _)) = self.table.get(gene) {
+            return Some(*role)
+        }
+        match (self.bait_pattern.is_match(gene), self.prey_pattern.is_match(gene)) {
+            (true, false) => Some(Role::Bait),
+            (false, true) => Some(Role::Prey),
+            // none or both matched
+            _ => None,
+        }
+    }
+
+    /// Symbol of gene in the annotation table, or the gene ID.
+    pub fn symbol<'a>(&'a self, gene: &'a str) -> &'a str {
+        match self.table.get(gene) {
+            Some((_, Some(symbol))) => symbol,
+            _ => gene,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "gene\trole\tsymbol
+# renamed clones
+bait_A\tprey\tGENE1
+prey_X\tbait\t
+ORF001\tBait\tGENE2
+";
+
+    fn roles() -> Roles {
+        let mut roles = Roles::new("^bait_", "^prey_").unwrap();
+        roles.read_table(TABLE.as_bytes());
+        roles
+    }
+
+    #[test]
+    fn test_table_over_pattern() {
+        let roles = roles();
+        assert_eq!(roles.n_annotated(), 3);
+        assert!(roles.role("bait_A") == Some(Role::Prey));
+        assert!(roles.role("prey_X") == Some(Role::Bait));
+        assert!(roles.role("ORF001") == Some(Role::Bait));
+        assert_eq!(roles.symbol("bait_A"), "GENE1");
+        // empty symbol column
+        assert_eq!(roles.symbol("prey_X"), "prey_X");
+    }
+
+    #[test]
+    fn test_pattern_fallback() {
+        let roles = roles();
+        assert!(roles.role("bait_B") == Some(Role::Bait));
+        assert!(roles.role("prey_Y") == Some(Role::Prey));
+        assert!(roles.role("ORF002").is_none());
+        assert_eq!(roles.symbol("bait_B"), "bait_B");
+        // none or both patterns matched
+        let roles = Roles::new("_B$", "^pY").unwrap();
+        assert!(roles.role("pX_B") == Some(Role::Bait));
+        assert!(roles.role("pY_A") == Some(Role::Prey));
+        assert!(roles.role("pY_B").is_none());
+        assert!(roles.role("pX_A").is_none());
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(Roles::new("^bait_(", "^prey_").is_err());
+        assert!(Roles::new("^bait_", "[prey").is_err());
+    }
+}
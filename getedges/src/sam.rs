@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use flate2::read::MultiGzDecoder;
+
+
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_SECONDARY: u16 = 0x100;
+pub const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+
+/// Value of an optional field, by its type.
+#[derive(Debug, PartialEq)]
+pub enum TagValue {
+    Char(char),
+    Int(i64),
+    Float(f32),
+    Str(String),
+    Hex(String),
+    IntArray(Vec<i64>),
+    FloatArray(Vec<f32>),
+}
+
+impl TagValue {
+    fn parse(tp: &str, value: &str) -> Result<Self, String> {
+        let int = |v: &str| v.parse::<i64>().map_err(|_| format!("invalid integer: {}", v));
+        let float = |v: &str| v.parse::<f32>().map_err(|_| format!("invalid float: {}", v));
+        let res = match tp {
+            "A" => {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => TagValue::Char(c),
+                    _ => return Err(format!("invalid character: {}", value)),
+                }
+            },
+            "i" => TagValue::Int(int(value)?),
+            "f" => TagValue::Float(float(value)?),
+            "Z" => TagValue::Str(value.to_string()),
+            "H" => TagValue::Hex(value.to_string()),
+            "B" => {
+                let mut items = value.split(',');
+                match items.next() {
+                    Some("f") => TagValue::FloatArray(items.map(float).collect::<Result<_, _>>()?),
+                    Some("c") | Some("C") | Some("s") | Some("S") | Some("i") | Some("I") => {
+                        TagValue::IntArray(items.map(int).collect::<Result<_, _>>()?)
+                    },
+                    _ => return Err(format!("invalid array: {}", value)),
+                }
+            },
+            _ => return Err(format!("unknown tag type: {}", tp)),
+        };
+        Ok(res)
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            TagValue::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            TagValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+
+/// An alignment line of SAM file, the fields not used are omitted.
+#[derive(Debug, PartialEq)]
+pub struct SamRecord {
+    pub qname: String,
+    pub flag: u16,
+    pub rname: String,
+    pub mapq: u8,
+    pub tags: Vec<(String, TagValue)>,
+}
+
+impl SamRecord {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let items: Vec<&str> = line.split('\t').collect();
+        if items.len() < 11 {
+            return Err(format!("expect at least 11 fields, got {}", items.len()))
+        }
+        let field = |i: usize, name: &str| -> Result<u32, String> {
+            items[i].parse().map_err(|_| format!("invalid {}: {}", name, items[i]))
+        };
+        let flag = field(1, "FLAG")?;
+        field(3, "POS")?;
+        let mapq = field(4, "MAPQ")?;
+        if flag > u16::MAX as u32 { return Err(format!("invalid FLAG: {}", flag)) }
+        if mapq > u8::MAX as u32 { return Err(format!("invalid MAPQ: {}", mapq)) }
+        let mut tags = vec![];
+        for item in &items[11..] {
+            let fields: Vec<&str> = item.splitn(3, ':').collect();
+            if fields.len() != 3 || fields[0].len() != 2 {
+                return Err(format!("invalid optional field: {}", item))
+            }
+            let value = TagValue::parse(fields[1], fields[2])
+                .map_err(|e| format!("optional field {}: {}", fields[0], e))?;
+            tags.push((fields[0].to_string(), value));
+        }
+        Ok(Self {
+            qname: items[0].to_string(),
+            flag: flag as u16,
+            rname: items[2].to_string(),
+            mapq: mapq as u8,
+            tags,
+        })
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&TagValue> {
+        self.tags.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.flag & FLAG_UNMAPPED != 0 || self.rname == "*"
+    }
+
+    /// Secondary or supplementary alignment, the read has another primary line.
+    pub fn is_extra(&self) -> bool {
+        self.flag & (FLAG_SECONDARY | FLAG_SUPPLEMENTARY) != 0
+    }
+}
+
+
+type Records = Box<dyn Iterator<Item=Result<SamRecord, String>>>;
+
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const BAM_MAGIC: &[u8] = b"BAM\x01";
+
+
+/// Leading bytes of the reader, not consumed.
+fn peek(reader: &mut dyn BufRead, n: usize) -> io::Result<Vec<u8>> {
+    let buf = reader.fill_buf()?;
+    Ok(buf[..buf.len().min(n)].to_vec())
+}
+
+
+/// Read the alignment records of SAM, gzipped SAM or BAM file (detected by content),
+/// `-` for stdin. Errors are reported with the line (or record) number.
+pub fn read_sam(path: &str) -> Result<Records, String> {
+    let input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?)
+    };
+    read_records(Box::new(BufReader::new(input)), path)
+}
+
+
+/// Records of the SAM, gzipped SAM or BAM stream, errors are prefixed with `path`.
+fn read_records(mut reader: Box<dyn BufRead>, path: &str) -> Result<Records, String> {
+    let io_err = |e: io::Error| format!("{}: {}", path, e);
+    // BAM is compressed in BGZF, a series of gzip members
+    if peek(&mut reader, 2).map_err(io_err)? == GZIP_MAGIC {
+        reader = Box::new(BufReader::new(MultiGzDecoder::new(reader)));
+    }
+    let path = path.to_string();
+    if peek(&mut reader, 4).map_err(io_err)? == BAM_MAGIC {
+        let bam = BamReader::new(reader).map_err(|e| format!("{}: BAM header: {}", path, e))?;
+        let records = bam.enumerate().map(move |(i, rec)| rec.map_err(|e| format!("{}: record {}: {}", path, i + 1, e)));
+        return Ok(Box::new(records))
+    }
+    let records = reader.lines().enumerate().filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => return Some(Err(format!("{}:{}: {}", path, i + 1, e))),
+        };
+        if line.is_empty() || line.starts_with('@') { return None }
+        Some(SamRecord::parse(&line).map_err(|e| format!("{}:{}: {}", path, i + 1, e)))
+    });
+    Ok(Box::new(records))
+}
+
+
+/// Little-endian fields of BAM binary data.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.data.len() {
+            return Err("unexpected end of record".to_string())
+        }
+        let res = &self.data[self.pos..self.pos+n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> { Ok(self.bytes(1)?[0]) }
+    fn u16(&mut self) -> Result<u16, String> { Ok(u16::from_le_bytes([self.u8()?, self.u8()?])) }
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn i32(&mut self) -> Result<i32, String> { Ok(self.u32()? as i32) }
+    fn f32(&mut self) -> Result<f32, String> { Ok(f32::from_bits(self.u32()?)) }
+
+    /// NUL terminated string.
+    fn cstr(&mut self) -> Result<String, String> {
+        let len = self.data[self.pos..].iter().position(|b| *b == 0).ok_or("string not terminated")?;
+        let s = String::from_utf8_lossy(self.bytes(len)?).to_string();
+        self.pos += 1;
+        Ok(s)
+    }
+
+    /// Integer of the type code in tags.
+    fn int(&mut self, tp: u8) -> Result<i64, String> {
+        let v = match tp {
+            b'c' => self.u8()? as i8 as i64,
+            b'C' => self.u8()? as i64,
+            b's' => self.u16()? as i16 as i64,
+            b'S' => self.u16()? as i64,
+            b'i' => self.i32()? as i64,
+            b'I' => self.u32()? as i64,
+            _ => return Err(format!("invalid integer type: {}", tp as char)),
+        };
+        Ok(v)
+    }
+
+    fn tag_value(&mut self, tp: u8) -> Result<TagValue, String> {
+        let v = match tp {
+            b'A' => TagValue::Char(self.u8()? as char),
+            b'f' => TagValue::Float(self.f32()?),
+            b'Z' => TagValue::Str(self.cstr()?),
+            b'H' => TagValue::Hex(self.cstr()?),
+            b'B' => {
+                let sub = self.u8()?;
+                let n = self.u32()? as usize;
+                if sub == b'f' {
+                    TagValue::FloatArray((0..n).map(|_| self.f32()).collect::<Result<_, _>>()?)
+                } else {
+                    TagValue::IntArray((0..n).map(|_| self.int(sub)).collect::<Result<_, _>>()?)
+                }
+            },
+            _ => TagValue::Int(self.int(tp)?),
+        };
+        Ok(v)
+    }
+}
+
+
+fn read_block(reader: &mut dyn BufRead, n: usize) -> Result<Vec<u8>, String> {
+    let mut buf = vec![0; n];
+    reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(buf)
+}
+
+
+fn read_len(reader: &mut dyn BufRead) -> Result<usize, String> {
+    let b = read_block(reader, 4)?;
+    let v = i32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    if v < 0 { return Err(format!("invalid length: {}", v)) }
+    Ok(v as usize)
+}
+
+
+/// Records of the decompressed BAM stream.
+struct BamReader {
+    reader: Box<dyn BufRead>,
+    ref_names: Vec<String>,
+}
+
+impl BamReader {
+    fn new(mut reader: Box<dyn BufRead>) -> Result<Self, String> {
+        read_block(&mut reader, 4)?;  // magic
+        let l_text = read_len(&mut reader)?;
+        read_block(&mut reader, l_text)?;
+        let n_ref = read_len(&mut reader)?;
+        let mut ref_names = Vec::with_capacity(n_ref);
+        for _ in 0..n_ref {
+            let l_name = read_len(&mut reader)?;
+            let name = read_block(&mut reader, l_name)?;
+            let name = name.split(|b| *b == 0).next().unwrap_or(&[]);
+            ref_names.push(String::from_utf8_lossy(name).to_string());
+            read_len(&mut reader)?;  // l_ref
+        }
+        Ok(Self { reader, ref_names })
+    }
+
+    fn parse(&self, data: &[u8]) -> Result<SamRecord, String> {
+        let mut c = Cursor { data, pos: 0 };
+        let ref_id = c.i32()?;
+        c.i32()?;  // pos
+        let l_read_name = c.u8()? as usize;
+        let mapq = c.u8()?;
+        c.u16()?;  // bin
+        let n_cigar_op = c.u16()? as usize;
+        let flag = c.u16()?;
+        let l_seq = c.u32()? as usize;
+        c.bytes(12)?;  // next refID, next pos, tlen
+        let qname = c.bytes(l_read_name)?;
+        let qname = String::from_utf8_lossy(&qname[..l_read_name.saturating_sub(1)]).to_string();
+        c.bytes(n_cigar_op * 4 + l_seq.div_ceil(2) + l_seq)?;
+        let rname = match ref_id {
+            -1 => "*".to_string(),
+            i => self.ref_names.get(i as usize).ok_or(format!("invalid reference id: {}", i))?.clone(),
+        };
+        let mut tags = vec![];
+        while c.pos < data.len() {
+            let name = String::from_utf8_lossy(c.bytes(2)?).to_string();
+            let tp = c.u8()?;
+            let value = c.tag_value(tp).map_err(|e| format!("optional field {}: {}", name, e))?;
+            tags.push((name, value));
+        }
+        Ok(SamRecord { qname, flag, rname, mapq, tags })
+    }
+}
+
+impl Iterator for BamReader {
+    type Item = Result<SamRecord, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut size = [0u8; 4];
+        match self.reader.read_exact(&mut size) {
+            Ok(_) => {},
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e.to_string())),
+        }
+        let mut data = vec![0; u32::from_le_bytes(size) as usize];
+        if let Err(e) = self.reader.read_exact(&mut data) {
+            return Some(Err(e.to_string()))
+        }
+        Some(self.parse(&data))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    const SAM: &str = "@SQ\tSN:bait_A\tLN:100\n@SQ\tSN:prey_X\tLN:100\n\
+        q1\t0\tbait_A\t1\t37\t4M\t*\t0\t0\tACGT\tIIII\tNM:i:0\tXA:Z:prey_X,+1,4M,1;\n\
+        q2\t4\t*\t0\t0\t*\t*\t0\t0\tAC\tII\n\
+        q3\t256\tprey_X\t1\t0\t3M\t*\t0\t0\tACG\tIII\tnM:i:-2\tXS:f:1.5\tXT:A:U\tBC:B:s,-1,300\n";
+
+    // BAM record with the cigar `{l_seq}M`, other fields not parsed are zero
+    fn bam_record(ref_id: i32, qname: &str, flag: u16, mapq: u8, l_seq: usize, tags: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        body.extend_from_slice(&ref_id.to_le_bytes());
+        body.extend_from_slice(&0i32.to_le_bytes());
+        body.push(qname.len() as u8 + 1);
+        body.push(mapq);
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&flag.to_le_bytes());
+        body.extend_from_slice(&(l_seq as u32).to_le_bytes());
+        body.extend_from_slice(&[0; 12]);
+        body.extend_from_slice(qname.as_bytes());
+        body.push(0);
+        body.extend_from_slice(&((l_seq as u32) << 4).to_le_bytes());
+        body.extend(vec![0x12; l_seq.div_ceil(2)]);
+        body.extend(vec![40; l_seq]);
+        body.extend_from_slice(tags);
+        let mut rec = (body.len() as u32).to_le_bytes().to_vec();
+        rec.extend(body);
+        rec
+    }
+
+    // BAM of the same records as `SAM`
+    fn bam() -> Vec<u8> {
+        let text = b"@SQ\tSN:bait_A\tLN:100\n@SQ\tSN:prey_X\tLN:100\n";
+        let mut data = BAM_MAGIC.to_vec();
+        data.extend_from_slice(&(text.len() as i32).to_le_bytes());
+        data.extend_from_slice(text);
+        data.extend_from_slice(&2i32.to_le_bytes());
+        for name in &["bait_A", "prey_X"] {
+            data.extend_from_slice(&(name.len() as i32 + 1).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&100i32.to_le_bytes());
+        }
+        data.extend(bam_record(0, "q1", 0, 37, 4, b"NMC\x00XAZprey_X,+1,4M,1;\x00"));
+        data.extend(bam_record(-1, "q2", 4, 0, 2, b""));
+        let mut tags = b"nMi".to_vec();
+        tags.extend_from_slice(&(-2i32).to_le_bytes());
+        tags.extend_from_slice(b"XSf");
+        tags.extend_from_slice(&1.5f32.to_le_bytes());
+        tags.extend_from_slice(b"XTAU");
+        tags.extend_from_slice(b"BCBs");
+        tags.extend_from_slice(&2u32.to_le_bytes());
+        tags.extend_from_slice(&(-1i16).to_le_bytes());
+        tags.extend_from_slice(&300i16.to_le_bytes());
+        data.extend(bam_record(1, "q3", 256, 0, 3, &tags));
+        data
+    }
+
+    // compressed in two gzip members, like the BGZF blocks
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut res = vec![];
+        for chunk in data.chunks(data.len() / 2 + 1) {
+            let mut gz = GzEncoder::new(vec![], Compression::default());
+            gz.write_all(chunk).unwrap();
+            res.extend(gz.finish().unwrap());
+        }
+        res
+    }
+
+    fn records(data: Vec<u8>) -> Vec<SamRecord> {
+        let reader = Box::new(BufReader::new(io::Cursor::new(data)));
+        read_records(reader, "test").unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_bam_same_as_sam() {
+        let sam = records(SAM.as_bytes().to_vec());
+        assert_eq!(sam.len(), 3);
+        assert_eq!(sam[2].tag("BC"), Some(&TagValue::IntArray(vec![-1, 300])));
+        assert_eq!(records(bam()), sam);
+        assert_eq!(records(gzip(&bam())), sam);
+        assert_eq!(records(gzip(SAM.as_bytes())), sam);
+    }
+
+    #[test]
+    fn test_truncated_bam() {
+        let data = bam();
+        let reader = Box::new(BufReader::new(io::Cursor::new(data[..data.len() - 5].to_vec())));
+        let res: Vec<_> = read_records(reader, "test").unwrap().collect();
+        assert!(res[2].is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::Write;
+use std::collections::BTreeMap;
+
+use crate::{ExtractParams, align_linker};
+
+
+/// Shuffle sequence with a fixed seed (xorshift), keep the base composition.
+fn shuffle(seq: &[u8], seed: u64) -> Vec<u8> {
+    let mut state = seed.max(1);
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut res = seq.to_vec();
+    for i in (1..res.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        res.swap(i, j);
+    }
+    res
+}
+
+
+/// Decoy linker patterns, shuffled from the real patterns.
+pub fn decoy_patterns(patterns: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    patterns.iter().enumerate().map(|(i, p)| shuffle(p, 0x9e37_79b9_7f4a_7c15 + i as u64)).collect()
+}
+
+
+fn best_score(seq: &[u8], patterns: &[Vec<u8>], params: &ExtractParams) -> i32 {
+    patterns.iter().map(|p| align_linker(seq, p, 0, seq.len(), params).score).max().unwrap()
+}
+
+
+/// Distribution of the best alignment scores of reads, to the real and decoy patterns.
+pub struct ScoreHist {
+    // score -> (reads, decoys)
+    counts: BTreeMap<i32, (u64, u64)>,
+    n: u64,
+    max_score: i32,
+}
+
+impl ScoreHist {
+    pub fn new(seqs: &[&[u8]], patterns: &[Vec<u8>], decoys: &[Vec<u8>], params: &ExtractParams) -> Self {
+        let mut counts: BTreeMap<i32, (u64, u64)> = BTreeMap::new();
+        for seq in seqs {
+            counts.entry(best_score(seq, patterns, params)).or_insert((0, 0)).0 += 1;
+            counts.entry(best_score(seq, decoys, params)).or_insert((0, 0)).1 += 1;
+        }
+        let max_score = patterns.iter().map(|p| p.len()).max().unwrap_or(0) as i32 * params.match_score;
+        Self { counts, n: seqs.len() as u64, max_score }
+    }
+
+    /// Fraction of reads and decoys scored at least `score`.
+    fn pass_ratio(&self, score: i32) -> (f64, f64) {
+        if self.n == 0 { return (0.0, 0.0) }
+        let (r, d) = self.counts.range(score..).fold((0, 0), |acc, (_, c)| (acc.0 + c.0, acc.1 + c.1));
+        (r as f64 / self.n as f64, d as f64 / self.n as f64)
+    }
+
+    /// Minimal score with the estimated false positive rate (decoys pass) not more than `max_fpr`.
+    pub fn recommend(&self, max_fpr: f64) -> i32 {
+        for s in self.counts.keys() {
+            if self.pass_ratio(*s).1 <= max_fpr { return *s }
+        }
+        // higher than all decoys
+        self.counts.keys().next_back().map(|s| s + 1).unwrap_or(0)
+    }
+
+    pub fn score_ratio(&self, score: i32) -> f32 {
+        score as f32 / self.max_score as f32
+    }
+
+    /// Estimated (reads pass, false positive rate) of a score threshold.
+    pub fn rates(&self, min_score: f32) -> (f64, f64) {
+        self.pass_ratio(min_score.ceil() as i32)
+    }
+
+    pub fn write_tsv(&self, path: &str) {
+        let mut f = File::create(path).unwrap();
+        let _ = writeln!(f, "score\tscore_ratio\treads\tdecoys\treads_pass\tdecoys_pass");
+        for (s, (r, d)) in &self.counts {
+            let (rp, dp) = self.pass_ratio(*s);
+            let _ = writeln!(f, "{}\t{:.3}\t{}\t{}\t{:.6}\t{:.6}", s, self.score_ratio(*s), r, d, rp, dp);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERN: &[u8] = b"GTTGGATAGCGTGCGGGTGCCAGGGCTCCAAC";
+
+    fn sorted(seq: &[u8]) -> Vec<u8> {
+        let mut s = seq.to_vec();
+        s.sort_unstable();
+        s
+    }
+
+    fn params() -> ExtractParams {
+        ExtractParams {
+            score_ratio_thresh: 0.6, max_edit_dist: None,
+            match_score: 1, mismatch_score: -1, gap_open: -1, gap_extend: -1,
+            max_n: 0, min_base_qual: 0, min_mean_qual: 0.0, max_expected_errors: f32::INFINITY,
+        }
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let a = shuffle(PATTERN, 42);
+        assert_eq!(a, shuffle(PATTERN, 42));
+        assert_ne!(a, PATTERN.to_vec());
+        assert_ne!(a, shuffle(PATTERN, 43));
+        assert_eq!(sorted(&a), sorted(PATTERN));
+        // seed 0 is not a fixed point of xorshift
+        assert_eq!(sorted(&shuffle(PATTERN, 0)), sorted(PATTERN));
+    }
+
+    #[test]
+    fn test_decoy_patterns() {
+        let patterns = vec![PATTERN.to_vec(), bio::alphabets::dna::revcomp(PATTERN)];
+        let decoys = decoy_patterns(&patterns);
+        assert_eq!(decoys, decoy_patterns(&patterns));
+        for (p, d) in patterns.iter().zip(&decoys) {
+            assert_eq!(d.len(), p.len());
+            assert_eq!(sorted(d), sorted(p));
+            assert_ne!(d, p);
+        }
+    }
+
+    #[test]
+    fn test_recommend() {
+        let mut counts = BTreeMap::new();
+        counts.insert(10, (5, 50));
+        counts.insert(15, (5, 40));
+        counts.insert(20, (10, 9));
+        counts.insert(25, (80, 1));
+        let hist = ScoreHist { counts, n: 100, max_score: 32 };
+        for (fpr, score) in [(1.0, 10), (0.5, 15), (0.1, 20), (0.01, 25), (0.001, 26)] {
+            let s = hist.recommend(fpr);
+            assert_eq!(s, score);
+            assert!(hist.rates(s as f32).1 <= fpr);
+        }
+        // the score threshold is rounded up
+        assert_eq!(hist.rates(19.2), (0.9, 0.1));
+        assert_eq!(hist.score_ratio(16), 0.5);
+    }
+
+    #[test]
+    fn test_calibrate_reads() {
+        let reads: Vec<Vec<u8>> = vec![
+            [b"ACGTAACCGGTTA", PATTERN, b"CCCAAATTTGGGC"].concat(),
+            [b"TTGACGTAACCGGTTA", PATTERN, b"CCCAAATTTG"].concat(),
+            [b"ACGTAACCGGTTA", &bio::alphabets::dna::revcomp(PATTERN)[..], b"CCCAAATTTGGGC"].concat(),
+            b"ACACACACACTTTTTTTTTTGGGGCCCCATATATATATCGCGCGCGAATTAATTCCGA".to_vec(),
+            b"CATGCATGCATGCATGCATGGATCGATCGATCAAAAAAAAAACCCCCCCCCTTTTTT".to_vec(),
+        ];
+        let seqs: Vec<&[u8]> = reads.iter().map(|r| r.as_slice()).collect();
+        let patterns = vec![PATTERN.to_vec(), bio::alphabets::dna::revcomp(PATTERN)];
+        let hist = ScoreHist::new(&seqs, &patterns, &decoy_patterns(&patterns), &params());
+        let score = hist.recommend(0.0);
+        let (pass, fpr) = hist.rates(score as f32);
+        assert_eq!(fpr, 0.0);
+        // the reads with linker pass
+        assert_eq!(pass, 0.6);
+    }
+}
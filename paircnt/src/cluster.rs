@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::seqkey::{SeqKey, compress_seq, compress_var, recover_seq};
+
+
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+
+/// A flank key corrected to the parent key of its cluster.
+pub struct Correction {
+    pub parent: SeqKey,
+    // the key stores the reverse complement relative to parent
+    pub flipped: bool,
+    pub distance: usize,
+}
+
+
+// all sequences within `max_dist` substitutions of `seq`, start from position `start`
+fn substitutions(seq: &mut Vec<u8>, start: usize, max_dist: usize, res: &mut Vec<Vec<u8>>) {
+    if max_dist == 0 { return }
+    for i in start..seq.len() {
+        let origin = seq[i];
+        for b in BASES.iter() {
+            if *b == origin { continue }
+            seq[i] = *b;
+            res.push(seq.clone());
+            substitutions(seq, i + 1, max_dist - 1, res);
+        }
+        seq[i] = origin;
+    }
+}
+
+
+fn hamming(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y).count()
+}
+
+
+/// Directional clustering of flank keys (as UMI-tools), the key `a` absorbs
+/// key `b` within `max_dist` mismatches when count(a) >= 2 * count(b) - 1.
+/// Keys are visited in descending order of counts, each cluster is formed by
+/// all keys reachable from the root through directional edges.
+/// Return the correction of each absorbed key to the root of its cluster.
+pub fn directional_cluster(
+        counts: &HashMap<SeqKey, u64>, k: usize,
+        max_dist: usize, canonical: bool) -> HashMap<SeqKey, Correction> {
+    let mut keys: Vec<(&SeqKey, &u64)> = counts.iter().collect();
+    keys.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    let mut corrections: HashMap<SeqKey, Correction> = HashMap::new();
+    let mut visited: HashSet<&SeqKey> = HashSet::new();
+    for (root, _) in keys {
+        if corrections.contains_key(root) || visited.contains(root) { continue }
+        visited.insert(root);
+        // sequences containing ambiguous bases are not clustered
+        if let SeqKey::Ambiguous(_) = root { continue }
+        let root_seq = recover_seq(root, k).into_bytes();
+        // neighbors of variable-length key have the same length
+        let compress = if let SeqKey::Var(_, _) = root { compress_var } else { compress_seq };
+        // (key, sequence in the orientation of root)
+        let mut queue: VecDeque<(SeqKey, Vec<u8>)> = VecDeque::new();
+        queue.push_back((root.clone(), root_seq.clone()));
+        while let Some((node, node_seq)) = queue.pop_front() {
+            let node_cnt = counts[&node];
+            let mut neighbors = vec![];
+            substitutions(&mut node_seq.clone(), 0, max_dist, &mut neighbors);
+            for nb_seq in neighbors {
+                let (nb, flipped) = compress(&nb_seq, canonical);
+                if visited.contains(&nb) || corrections.contains_key(&nb) { continue }
+                let nb_cnt = match counts.get(&nb) {
+                    Some(c) => *c,
+                    None => continue,
+                };
+                if node_cnt < 2 * nb_cnt - 1 { continue }
+                corrections.insert(nb.clone(), Correction {
+                    parent: root.clone(),
+                    flipped,
+                    distance: hamming(&root_seq, &nb_seq),
+                });
+                queue.push_back((nb, nb_seq));
+            }
+        }
+    }
+    corrections
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bio::alphabets::dna::revcomp;
+
+    fn counts(seqs: &[(&[u8], u64)], canonical: bool) -> HashMap<SeqKey, u64> {
+        seqs.iter().map(|(s, c)| (compress_seq(s, canonical).0, *c)).collect()
+    }
+
+    fn key(seq: &[u8], canonical: bool) -> SeqKey {
+        compress_seq(seq, canonical).0
+    }
+
+    #[test]
+    fn test_absorb_by_count() {
+        let c = counts(&[(b"ACGTACGTACGTA", 100), (b"ACGTACGAACGTA", 3), (b"TCGTACGTACGTA", 60)], false);
+        let corrections = directional_cluster(&c, 13, 1, false);
+        assert_eq!(corrections.len(), 1);
+        let corr = &corrections[&key(b"ACGTACGAACGTA", false)];
+        assert_eq!(corr.parent, key(b"ACGTACGTACGTA", false));
+        assert_eq!(corr.distance, 1);
+        assert!(!corr.flipped);
+    }
+
+    #[test]
+    fn test_chain_to_root() {
+        // the second is absorbed by the root, the third by the second, within 1 mismatch
+        let c = counts(&[(b"AAAAAAAAAAAAA", 100), (b"AAAAAAAAAAAAC", 10), (b"AAAAAAAAAAAGC", 3)], false);
+        let corrections = directional_cluster(&c, 13, 1, false);
+        assert_eq!(corrections.len(), 2);
+        let corr = &corrections[&key(b"AAAAAAAAAAAGC", false)];
+        assert_eq!(corr.parent, key(b"AAAAAAAAAAAAA", false));
+        assert_eq!(corr.distance, 2);
+        // not within the distance of root directly
+        let corrections = directional_cluster(&counts(&[(b"AAAAAAAAAAAAA", 100), (b"AAAAAAAAAAAGC", 3)], false), 13, 1, false);
+        assert!(corrections.is_empty());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let parent: &[u8] = b"ACCGTTAGCATGA";
+        let child = revcomp(b"ACCGTTAGCATGT");
+        let c = counts(&[(parent, 50), (&child, 2)], true);
+        let corrections = directional_cluster(&c, 13, 1, true);
+        let corr = &corrections[&key(&child, true)];
+        assert_eq!(corr.parent, key(parent, true));
+        // the key of child stores the reverse complement relative to parent, if they are stored differently
+        let flipped = compress_seq(parent, true).1 != compress_seq(b"ACCGTTAGCATGT", true).1;
+        assert_eq!(corr.flipped, flipped);
+    }
+
+    #[test]
+    fn test_var_keys() {
+        let c: HashMap<SeqKey, u64> = [(&b"ACGTACGTAC"[..], 20), (b"ACGTACGTAA", 2), (b"ACGTACGTACA", 2)]
+            .iter().map(|(s, n)| (compress_var(s, false).0, *n)).collect();
+        let corrections = directional_cluster(&c, 0, 1, false);
+        // only the neighbor of the same length
+        assert_eq!(corrections.len(), 1);
+        assert!(corrections.contains_key(&compress_var(b"ACGTACGTAA", false).0));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+
+pub const UNDETERMINED: &str = "undetermined";
+
+
+/// Index sequence in the read header, like `1:N:0:TTAGGC` (or `1:N:0:TTAGGC+CTTGTA` for dual index).
+pub fn header_index(desc: Option<&str>) -> Option<&[u8]> {
+    let field = desc?.split_whitespace().next()?;
+    let index = field.rsplit(':').next()?;
+    if index.is_empty() { None } else { Some(index.as_bytes()) }
+}
+
+
+fn mismatches(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| !x.eq_ignore_ascii_case(y)).count()
+}
+
+
+/// Samples and their index sequences, the last sample is the undetermined bucket.
+pub struct SampleSheet {
+    pub names: Vec<String>,
+    indexes: Vec<Vec<u8>>,
+    max_mismatch: usize,
+}
+
+impl SampleSheet {
+    /// Read the sample sheet, each line is `<sample name>\t<index sequence>`.
+    /// Empty lines and lines start with `#` are ignored.
+    pub fn from_tsv(path: &str, max_mismatch: usize) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_reader(BufReader::new(file), max_mismatch).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn from_reader<R: BufRead>(reader: R, max_mismatch: usize) -> Result<Self, String> {
+        let mut names = vec![];
+        let mut indexes: Vec<Vec<u8>> = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') { continue }
+            let items: Vec<&str> = line.split('\t').map(|s| s.trim()).collect();
+            if items.len() != 2 {
+                return Err(format!("line {}: expect 2 columns (sample, index), got: {}", i + 1, line));
+            }
+            let (name, index) = (items[0].to_string(), items[1].to_uppercase().into_bytes());
+            if name == UNDETERMINED || names.contains(&name) {
+                return Err(format!("line {}: duplicated or reserved sample name: {}", i + 1, name));
+            }
+            if let Some(j) = indexes.iter().position(|x| x.len() == index.len() && mismatches(x, &index) <= 2 * max_mismatch) {
+                return Err(format!("line {}: index of {} is within {} mismatches of {}, can't be distinguished.",
+                    i + 1, name, 2 * max_mismatch, names[j]));
+            }
+            names.push(name);
+            indexes.push(index);
+        }
+        if names.is_empty() {
+            return Err("no sample in sample sheet".to_string());
+        }
+        names.push(UNDETERMINED.to_string());
+        Ok(Self { names, indexes, max_mismatch })
+    }
+
+    pub fn undetermined(&self) -> usize {
+        self.names.len() - 1
+    }
+
+    /// Sample of the read index, the undetermined bucket if it's not within `max_mismatch`
+    /// of any sample index (the sample indexes are more than `2 * max_mismatch` apart).
+    pub fn assign(&self, index: Option<&[u8]>) -> usize {
+        let index = match index {
+            Some(i) => i,
+            None => return self.undetermined(),
+        };
+        self.indexes.iter()
+            .position(|x| x.len() == index.len() && mismatches(x, index) <= self.max_mismatch)
+            .unwrap_or_else(|| self.undetermined())
+    }
+}
+
+
+/// Number of reads of each sample.
+pub struct DemuxSummary<'a> {
+    pub names: &'a [String],
+    pub reads: Vec<u64>,
+}
+
+impl<'a> fmt::Display for DemuxSummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: u64 = self.reads.iter().sum();
+        writeln!(f, "Demultiplex result:")?;
+        for (name, n) in self.names.iter().zip(&self.reads) {
+            let ratio = if total == 0 { 0.0 } else { (n * 100) as f64 / total as f64 };
+            writeln!(f, "    {}\t{}\t{:.2}%", name, n, ratio)?;
+        }
+        writeln!(f, "total reads: {}", total)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(tsv: &str, max_mismatch: usize) -> Result<SampleSheet, String> {
+        SampleSheet::from_reader(tsv.as_bytes(), max_mismatch)
+    }
+
+    #[test]
+    fn test_header_index() {
+        assert_eq!(header_index(Some("1:N:0:ACGTAC")), Some(&b"ACGTAC"[..]));
+        assert_eq!(header_index(Some("1:N:0:ACGT+TTGA")), Some(&b"ACGT+TTGA"[..]));
+        // extra fields after the Illumina comment
+        assert_eq!(header_index(Some("2:Y:18:ACGTAC BC:Z:x")), Some(&b"ACGTAC"[..]));
+        assert_eq!(header_index(Some("1:N:0:")), None);
+        assert_eq!(header_index(None), None);
+    }
+
+    #[test]
+    fn test_assign() {
+        let s = sheet("# name\tindex\nS1\tACGTAC\nS2\tTTGACA\n\nS3\tACGT+TTGA\n", 1).unwrap();
+        assert_eq!(s.names, vec!["S1", "S2", "S3", UNDETERMINED]);
+        assert_eq!(s.assign(Some(b"ACGTAC")), 0);
+        // mismatch tolerance, case insensitive
+        assert_eq!(s.assign(Some(b"ACGTAG")), 0);
+        assert_eq!(s.assign(Some(b"ttgacc")), 1);
+        assert_eq!(s.assign(Some(b"ACGA+TTGA")), 2);
+        // too many mismatches, different length or no index
+        assert_eq!(s.assign(Some(b"ACGTGG")), s.undetermined());
+        assert_eq!(s.assign(Some(b"ACGTA")), s.undetermined());
+        assert_eq!(s.assign(None), s.undetermined());
+        // N in read index is a mismatch
+        assert_eq!(s.assign(Some(b"NCGTAC")), 0);
+        assert_eq!(s.assign(Some(b"NCGTAN")), s.undetermined());
+    }
+
+    #[test]
+    fn test_sheet_errors() {
+        // indexes within 2 * max_mismatch can't be distinguished
+        assert!(sheet("S1\tACGTAC\nS2\tACGTGG\n", 1).is_err());
+        assert!(sheet("S1\tACGTAC\nS2\tACGTGG\n", 0).is_ok());
+        assert!(sheet("S1\tACGTAC\nS1\tTTGACA\n", 1).is_err());
+        assert!(sheet("undetermined\tACGTAC\n", 1).is_err());
+        assert!(sheet("S1\tACGTAC\textra\n", 1).is_err());
+        assert!(sheet("# empty\n", 1).is_err());
+        assert!(SampleSheet::from_tsv("/nonexistent/samples.tsv", 1).is_err());
+    }
+}
@@ -2,14 +2,12 @@ use std::fs::File;
 use std::io::Write;
 use std::str;
 use std::collections::HashMap;
-use std::mem;
 use std::fmt;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::{Mutex, Arc};
-use std::time::Duration;
 use std::io::Read;
-use std::collections::HashSet;
+use std::process;
 
 
 extern crate bio;
@@ -18,97 +16,511 @@ extern crate flate2;
 extern crate log;
 extern crate simple_logger;
 
+mod calibrate;
+mod cluster;
+mod demux;
+mod merge;
+mod prefilter;
+mod seqkey;
+mod structure;
+mod trim;
+
 use clap::{Arg, App};
 use bio::alignment::pairwise::Aligner;
-use bio::alignment::Alignment;
+use bio::alignment::{Alignment, AlignmentOperation};
 use bio::io::fastq;
 use bio::alphabets::dna::revcomp;
-use log::{info};
+use log::{info, warn, error};
 use flate2::read::GzDecoder;
 
+use calibrate::{ScoreHist, decoy_patterns};
+use cluster::{directional_cluster, Correction};
+use demux::{SampleSheet, DemuxSummary, header_index};
+use merge::{merge_mates, MergeCounter};
+use prefilter::{Prefilter, PrefilterCheck, Window};
+use seqkey::{SeqKey, compress_seq, compress_var, recover_seq, n_count};
+use structure::{FlankBoundary, Layout, ReadStructure, WILDCARD};
+use trim::{Trimmer, TrimCounter, ADAPTER_R1, ADAPTER_R2};
+
+
+const QUAL_OFFSET: u8 = 33;
+
+
+/// Flanking sequence with its base qualities.
+#[derive(Clone, PartialEq)]
+struct Flank {
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+impl Flank {
+    fn new(seq: &[u8], qual: &[u8]) -> Self {
+        Self { seq: seq.to_vec(), qual: qual.to_vec() }
+    }
+
+    fn revcomp(&self) -> Self {
+        Self {
+            seq: revcomp(&self.seq),
+            qual: self.qual.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn is_low_quality(&self, params: &ExtractParams) -> bool {
+        if self.qual.is_empty() { return false }
+        let phred = self.qual.iter().map(|q| q.saturating_sub(QUAL_OFFSET));
+        let min_q = phred.clone().min().unwrap();
+        let mean_q = phred.clone().map(|q| q as f32).sum::<f32>() / self.qual.len() as f32;
+        let expected_errors: f32 = phred.map(|q| 10f32.powf(-(q as f32) / 10.0)).sum();
+        min_q < params.min_base_qual ||
+            mean_q < params.min_mean_qual ||
+            expected_errors > params.max_expected_errors
+    }
+}
+
 
-const SEQ_NT4_TABLE: [u64; 256] = [
-    0, 1, 2, 3,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 0, 4, 1,  4, 4, 4, 2,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  3, 3, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 0, 4, 1,  4, 4, 4, 2,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  3, 3, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
-    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4
-];
-
-const IDX_TABLE: [u8; 4] = [
-    b'A', b'C', b'G', b'T'
-];
-
-fn compress_seq(seq: &[u8]) -> Result<u64, &str> {
-    let mut res: u64 = 0;
-    let mut res_rc: u64 = 0;
-    let end = seq.len() - 1;
-    for i in 0..seq.len() {
-        if i >= 32 {
-            return Err("Seq can't longer than 32.")
-        }
-        let m = SEQ_NT4_TABLE[seq[i] as usize];
-        res |= m << i*2;
-        res_rc |= (3 - m) << (end - i)*2;
-    }
-    if res > res_rc { mem::swap(&mut res, &mut res_rc) };
-    Ok(res)
-}
-
-fn recover_seq(code: u64, k: u8) -> String {
-    let mut chars: Vec<u8> = Vec::with_capacity(k as usize);
-    for i in 0..k {
-        let mask: u64 = 3 << (i*2);
-        let idx = (code & mask) >> (i*2);
-        let b = IDX_TABLE[idx as usize];
-        chars.push(b);
-    }
-    String::from_utf8(chars).unwrap()
+#[derive(Clone, Copy)]
+struct ExtractParams {
+    score_ratio_thresh: f32,
+    // linker is found by edit distance instead of score ratio
+    max_edit_dist: Option<usize>,
+    // alignment scoring scheme
+    match_score: i32,
+    mismatch_score: i32,
+    gap_open: i32,
+    gap_extend: i32,
+    max_n: usize,
+    // quality thresholds of each flank
+    min_base_qual: u8,
+    min_mean_qual: f32,
+    max_expected_errors: f32,
 }
 
+impl ExtractParams {
+    fn min_score(&self, pattern_len: usize) -> f32 {
+        (pattern_len as i32 * self.match_score) as f32 * self.score_ratio_thresh
+    }
 
+    fn is_linker(&self, alignment: &Alignment, pattern: &[u8]) -> bool {
+        match self.max_edit_dist {
+            Some(d) => edit_count(alignment, pattern) <= d,
+            None => alignment.score as f32 >= self.min_score(alignment.xlen),
+        }
+    }
+
+    /// Max edits of the alignments pass the threshold, None if it can't be bounded.
+    fn max_edits(&self, pattern_len: usize) -> Option<usize> {
+        if let Some(d) = self.max_edit_dist { return Some(d) }
+        // a mismatch or gap base lose at least this score, compared with a match
+        let min_cost = (self.match_score - self.mismatch_score).min(-self.gap_extend);
+        if min_cost <= 0 { return None }
+        let max_score = (pattern_len as i32 * self.match_score) as f32;
+        let d = ((max_score - self.min_score(pattern_len)) / min_cost as f32).floor().max(0.0) as usize;
+        Some(d)
+    }
+}
+
+
+// substitutions at the wildcard positions of pattern are not edits
+fn edit_count(alignment: &Alignment, pattern: &[u8]) -> usize {
+    let mut x = alignment.xstart;
+    let mut n = 0;
+    for op in &alignment.operations {
+        match op {
+            AlignmentOperation::Match => x += 1,
+            AlignmentOperation::Subst => {
+                if pattern[x] != WILDCARD { n += 1 }
+                x += 1;
+            },
+            AlignmentOperation::Ins => {
+                n += 1;
+                x += 1;
+            },
+            AlignmentOperation::Del => n += 1,
+            _ => {},
+        }
+    }
+    n
+}
+
+
+#[derive(PartialEq)]
 enum ExtractRes {
-    Ok(String, String),
+    Ok(Flank, Flank),
     ScoreTooLow,
     LeftTooShort,
-    RightTooShort,
+    // carry the left flank, for rescuing by the mate read
+    RightTooShort(Flank),
+    TooManyN,
+    LowQuality,
+    // read is too short after adapter and poly-G trimming, not searched
+    TrimmedTooShort,
+    // more than one linker in read, carry the complete flank pairs of each linker
+    // and whether it's on the reverse strand
+    Concatemer(Vec<(Flank, Flank, bool)>),
 }
 
 
-fn extract_pet(seq: &[u8], pattern: &[u8], flanking: u8, score_ratio_thresh: f32) -> (ExtractRes, Alignment) {
-    // align linker to read
-    let score = |a: u8, b: u8| if a == b {1i32} else {-1i32};
-    let mut aligner = Aligner::with_capacity(seq.len(), pattern.len(), -1, -1, score);
-    let alignment = aligner.semiglobal(pattern, seq);
+// discard the pair if any flank contains too many ambiguous bases or with low quality
+fn filter_flanks(res: ExtractRes, params: &ExtractParams) -> ExtractRes {
+    match res {
+        ExtractRes::Ok(left, right) => {
+            if n_count(&left.seq) > params.max_n || n_count(&right.seq) > params.max_n {
+                ExtractRes::TooManyN
+            } else if left.is_low_quality(params) || right.is_low_quality(params) {
+                ExtractRes::LowQuality
+            } else {
+                ExtractRes::Ok(left, right)
+            }
+        },
+        _ => res,
+    }
+}
+
+
+/// Linker patterns to search and their layouts, with the optional prefilter locating them in read.
+struct Linkers {
+    layouts: Vec<Layout>,
+    // extract variable-length flanks
+    boundary: Option<FlankBoundary>,
+    prefilter: Option<Prefilter>,
+}
+
 
+/// Semiglobal alignment of pattern to seq[start..end], positions are relative to the whole seq.
+/// The wildcard bases of pattern match any base.
+fn align_linker(seq: &[u8], pattern: &[u8], start: usize, end: usize, params: &ExtractParams) -> Alignment {
+    let region = &seq[start..end];
+    let (match_score, mismatch_score) = (params.match_score, params.mismatch_score);
+    let score = |a: u8, b: u8| if a == b || a == WILDCARD {match_score} else {mismatch_score};
+    let mut aligner = Aligner::with_capacity(region.len(), pattern.len(), params.gap_open, params.gap_extend, score);
+    let mut alignment = aligner.semiglobal(pattern, region);
+    alignment.ystart += start;
+    alignment.yend += start;
+    alignment.ylen = seq.len();
+    alignment
+}
+
+
+fn extract_pet(
+        seq: &[u8], qual: &[u8], alignment: Alignment,
+        layout: &Layout, boundary: Option<&FlankBoundary>,
+        params: &ExtractParams) -> (ExtractRes, Alignment) {
     // filter out non matched reads
-    if (alignment.score as f32) < pattern.len() as f32 * score_ratio_thresh { 
+    if !params.is_linker(&alignment, &layout.pattern) {
         return (ExtractRes::ScoreTooLow, alignment)
     }
-    // filter out incomplete flanking
-    if (alignment.ystart as u8) < flanking {
+    // filter out incomplete flanking, variable-length flanks should not shorter than the layout
+    let (offset, min_len) = Layout::flank(&layout.right);
+    let s = alignment.yend + offset;
+    let len = match boundary {
+        Some(b) => b.right_len(seq, s),
+        None => min_len,
+    };
+    let right = if len < min_len || s + len > alignment.ylen {
+        None
+    } else {
+        Some(Flank::new(&seq[s..s+len], &qual[s..s+len]))
+    };
+    let (offset, min_len) = Layout::flank(&layout.left);
+    if alignment.ystart < offset + min_len {
+        return (ExtractRes::LeftTooShort, alignment)
+    }
+    let e = alignment.ystart - offset;
+    let len = match boundary {
+        Some(b) => b.left_len(seq, e),
+        None => min_len,
+    };
+    if len < min_len {
         return (ExtractRes::LeftTooShort, alignment)
     }
-    let s = alignment.ystart - flanking as usize;
-    let left = String::from_utf8(seq[s..alignment.ystart].to_vec()).unwrap();
-    let e = alignment.yend + flanking as usize;
-    if e > alignment.ylen {
-        return (ExtractRes::RightTooShort, alignment)
+    let left = Flank::new(&seq[e-len..e], &qual[e-len..e]);
+    match right {
+        Some(right) => (filter_flanks(ExtractRes::Ok(left, right), params), alignment),
+        None => (ExtractRes::RightTooShort(left), alignment),
+    }
+}
+
+
+fn search_read(seq: &[u8], qual: &[u8], linkers: &Linkers, params: &ExtractParams) -> Vec<(ExtractRes, Alignment)> {
+    let mut align_res: Vec<(ExtractRes, Alignment)> = Vec::with_capacity(linkers.layouts.len());
+    for (i, layout) in linkers.layouts.iter().enumerate() {
+        let pattern = &layout.pattern;
+        let window = match &linkers.prefilter {
+            Some(p) => p.window(i, seq),
+            None => Window::Ambiguous,
+        };
+        let alignment = match window {
+            Window::NoHit => {
+                // skip the aligner, score can't reach the threshold
+                let alignment = Alignment { xlen: pattern.len(), ylen: seq.len(), ..Default::default() };
+                align_res.push((ExtractRes::ScoreTooLow, alignment));
+                continue
+            },
+            Window::Region(start, end) => align_linker(seq, pattern, start, end, params),
+            Window::Ambiguous => align_linker(seq, pattern, 0, seq.len(), params),
+        };
+        align_res.push(extract_pet(seq, qual, alignment, layout, linkers.boundary.as_ref(), params));
+        // stop when linker is found
+        if !matches!(align_res[align_res.len()-1].0, ExtractRes::ScoreTooLow) {
+            break
+        }
+    }
+
+    // look for other linkers in the rest of read
+    let last = align_res.len() - 1;
+    if !matches!(align_res[last].0, ExtractRes::ScoreTooLow) {
+        let found = align_res[last].1.clone();
+        let mut hits = vec![];
+        find_linkers(seq, 0, found.ystart, linkers, params, &mut hits);
+        find_linkers(seq, found.yend, seq.len(), linkers, params, &mut hits);
+        if !hits.is_empty() {
+            hits.push((last, found));
+            align_res[last].0 = ExtractRes::Concatemer(concatemer_pairs(seq, qual, hits, linkers, params));
+        }
+    }
+    align_res
+}
+
+
+/// Find all non-overlapping linkers in seq[start..end], as (pattern index, alignment).
+fn find_linkers(
+        seq: &[u8], start: usize, end: usize,
+        linkers: &Linkers, params: &ExtractParams,
+        hits: &mut Vec<(usize, Alignment)>) {
+    if start >= end { return }
+    for (i, layout) in linkers.layouts.iter().enumerate() {
+        let pattern = &layout.pattern;
+        // the pattern is aligned entirely, at least (pattern length - region length) bases are deleted
+        if let Some(d) = params.max_edits(pattern.len()) {
+            if end - start + d < pattern.len() { continue }
+        }
+        let window = match &linkers.prefilter {
+            Some(p) => p.window(i, &seq[start..end]),
+            None => Window::Ambiguous,
+        };
+        let alignment = match window {
+            Window::NoHit => continue,
+            Window::Region(s, e) => align_linker(seq, pattern, start + s, start + e, params),
+            Window::Ambiguous => align_linker(seq, pattern, start, end, params),
+        };
+        if params.is_linker(&alignment, pattern) {
+            let (s, e) = (alignment.ystart, alignment.yend);
+            hits.push((i, alignment));
+            find_linkers(seq, start, s, linkers, params, hits);
+            find_linkers(seq, e, end, linkers, params, hits);
+            return
+        }
+    }
+}
+
+
+/// Extract the flank pairs of each linker in a concatemer read,
+/// the flanks can't extend over the neighbouring linkers.
+fn concatemer_pairs(
+        seq: &[u8], qual: &[u8], mut hits: Vec<(usize, Alignment)>,
+        linkers: &Linkers, params: &ExtractParams) -> Vec<(Flank, Flank, bool)> {
+    hits.sort_by_key(|(_, a)| a.ystart);
+    let mut pairs = vec![];
+    for j in 0..hits.len() {
+        let lo = if j == 0 { 0 } else { hits[j-1].1.yend };
+        let hi = if j + 1 == hits.len() { seq.len() } else { hits[j+1].1.ystart };
+        let (i, mut alignment) = hits[j].clone();
+        alignment.ystart -= lo;
+        alignment.yend -= lo;
+        alignment.ylen = hi - lo;
+        let layout = &linkers.layouts[i];
+        let res = extract_pet(&seq[lo..hi], &qual[lo..hi], alignment, layout, linkers.boundary.as_ref(), params);
+        if let ExtractRes::Ok(left, right) = res.0 {
+            pairs.push((left, right, i == 1));
+        }
+    }
+    pairs
+}
+
+
+/// Prefilter and full alignment agree on the extraction result, and the alignment position if linker found.
+fn same_result(a: &[(ExtractRes, Alignment)], b: &[(ExtractRes, Alignment)]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|((r1, a1), (r2, a2))| {
+        r1 == r2 && (matches!(r1, ExtractRes::ScoreTooLow) ||
+            (a1.score, a1.ystart, a1.yend) == (a2.score, a2.ystart, a2.yend))
+    })
+}
+
+
+/// Combine the search results of R1 and R2 of a read pair.
+/// R2 is read from the opposite strand, so its flanks are reverse complement
+/// of the R1 flanks on the other side of linker.
+/// R2 only rescues R1 truncated at 3' (linker not reached, or right flank incomplete).
+/// If R1 starts inside the left flank, the fragment ends there, and R2 reads through it
+/// into the adapter, the flank of R2 on that side is not from the library.
+/// Return the results to be reported and whether the pair was rescued by R2.
+fn combine_mates(
+        mut res1: Vec<(ExtractRes, Alignment)>,
+        res2: Vec<(ExtractRes, Alignment)>,
+        params: &ExtractParams) -> (Vec<(ExtractRes, Alignment)>, bool) {
+    let last1 = res1.len() - 1;
+    let last2 = res2.len() - 1;
+    let r1_truncated = matches!(res1[last1].0, ExtractRes::ScoreTooLow | ExtractRes::RightTooShort(_));
+    let combined = match (&res1[last1].0, &res2[last2].0) {
+        // the other mate may only cover one of the linkers
+        (ExtractRes::Concatemer(_), _) => return (res1, false),
+        (_, ExtractRes::Concatemer(_)) if r1_truncated => return (res2, false),
+        (ExtractRes::Ok(_, _), _) => return (res1, false),
+        (_, ExtractRes::Ok(_, _)) if r1_truncated => return (res2, true),
+        // R1 covers the left flank, R2 covers the right flank
+        (ExtractRes::RightTooShort(l1), ExtractRes::RightTooShort(l2)) => {
+            Some(ExtractRes::Ok(l1.clone(), l2.revcomp()))
+        },
+        _ => None,
+    };
+    match combined {
+        Some(res) => {
+            res1[last1].0 = filter_flanks(res, params);
+            (res1, true)
+        },
+        None => {
+            // report the failure reason of R2 only if linker is not found in R1
+            let r1_not_found = matches!(res1[last1].0, ExtractRes::ScoreTooLow | ExtractRes::TrimmedTooShort);
+            let r2_not_found = matches!(res2[last2].0, ExtractRes::ScoreTooLow | ExtractRes::TrimmedTooShort);
+            if r1_not_found && !r2_not_found { (res2, false) } else { (res1, false) }
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Strand {
+    Forward,
+    Reverse,
+    // not recorded, in canonical (not oriented) mode
+    Unknown,
+}
+
+impl fmt::Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Strand::Forward => "+",
+            Strand::Reverse => "-",
+            Strand::Unknown => ".",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+
+// key of flank, with the qualities in the same orientation as key
+type FlankKey = (SeqKey, Vec<u8>);
+
+
+fn flank_key(flank: &Flank, canonical: bool, var_len: bool) -> FlankKey {
+    let (key, flipped) = if var_len {
+        compress_var(&flank.seq, canonical)
+    } else {
+        compress_seq(&flank.seq, canonical)
+    };
+    let qual = if flipped { flank.qual.iter().rev().cloned().collect() } else { flank.qual.clone() };
+    (key, qual)
+}
+
+
+/// Make the key of a left-right pair.
+/// In oriented mode, flanks are transformed to the orientation of the construct
+/// (the linker pattern), so the left flank is always on the bait side.
+/// Otherwise, both flanks are canonicalized and sorted.
+fn pair_key(left: &Flank, right: &Flank, reverse: bool, oriented: bool, var_len: bool) -> (FlankKey, FlankKey, Strand) {
+    if oriented {
+        if reverse {
+            (flank_key(&right.revcomp(), false, var_len), flank_key(&left.revcomp(), false, var_len), Strand::Reverse)
+        } else {
+            (flank_key(left, false, var_len), flank_key(right, false, var_len), Strand::Forward)
+        }
+    } else {
+        let (k0, k1) = (flank_key(left, true, var_len), flank_key(right, true, var_len));
+        if k0.0 > k1.0 { (k1, k0, Strand::Unknown) } else { (k0, k1, Strand::Unknown) }
+    }
+}
+
+
+/// Sum of base qualities of all flanks share the same key.
+struct QualSum {
+    sum: Vec<u64>,
+    n: u64,
+}
+
+impl QualSum {
+    fn new() -> Self {
+        Self { sum: vec![], n: 0 }
     }
-    let right = String::from_utf8(seq[alignment.yend..e].to_vec()).unwrap();
 
-    (ExtractRes::Ok(left, right), alignment)
+    fn add(&mut self, qual: &[u8]) {
+        if self.sum.len() < qual.len() {
+            self.sum.resize(qual.len(), 0);
+        }
+        for (s, q) in self.sum.iter_mut().zip(qual) {
+            *s += q.saturating_sub(QUAL_OFFSET) as u64;
+        }
+        self.n += 1;
+    }
+
+    fn merge(&mut self, other: &QualSum, reverse: bool) {
+        if self.sum.len() < other.sum.len() {
+            self.sum.resize(other.sum.len(), 0);
+        }
+        let len = other.sum.len();
+        for i in 0..len {
+            let j = if reverse { len - 1 - i } else { i };
+            self.sum[j] += other.sum[i];
+        }
+        self.n += other.n;
+    }
+
+    fn mean(&self) -> Vec<u8> {
+        self.sum.iter().map(|s| (s / self.n) as u8 + QUAL_OFFSET).collect()
+    }
+}
+
+
+// counts of left-right pairs
+type PairFreq = HashMap<(SeqKey, SeqKey, Strand), u64>;
+
+
+/// Fold the counts of corrected flank keys into their parent keys.
+fn correct_pairs(
+        freq: PairFreq,
+        qual_sums: HashMap<SeqKey, QualSum>,
+        corrections: &HashMap<SeqKey, Correction>,
+        oriented: bool) -> (PairFreq, HashMap<SeqKey, QualSum>) {
+    let correct = |k: SeqKey| match corrections.get(&k) {
+        Some(c) => c.parent.clone(),
+        None => k,
+    };
+    let mut new_freq = HashMap::new();
+    for ((k0, k1, strand), v) in freq {
+        let (k0, k1) = (correct(k0), correct(k1));
+        let key = if !oriented && k0 > k1 { (k1, k0, strand) } else { (k0, k1, strand) };
+        *new_freq.entry(key).or_insert(0) += v;
+    }
+    let mut new_qual_sums: HashMap<SeqKey, QualSum> = HashMap::new();
+    let mut children = vec![];
+    for (k, q) in qual_sums {
+        match corrections.get(&k) {
+            Some(c) => children.push((c, q)),
+            None => { new_qual_sums.insert(k, q); },
+        }
+    }
+    for (c, q) in children {
+        new_qual_sums.get_mut(&c.parent).unwrap().merge(&q, c.flipped);
+    }
+    (new_freq, new_qual_sums)
+}
+
+
+fn mate_name(id: &str) -> &str {
+    if id.ends_with("/1") || id.ends_with("/2") {
+        &id[..id.len()-2]
+    } else {
+        id
+    }
 }
 
 
@@ -117,6 +529,16 @@ struct ResCounter {
     score_too_low: u64,
     left_too_short: u64,
     right_too_short: u64,
+    too_many_n: u64,
+    low_quality: u64,
+    trimmed_too_short: u64,
+    concatemer: u64,
+    // complete flank pairs in the concatemer reads
+    concatemer_pairs: u64,
+    // linker reads recovered with the help of R2, in paired-end mode
+    rescued_by_r2: u64,
+    // reads (pairs) parsed from input files, should equal to the total of categories
+    input_reads: u64,
 }
 
 impl ResCounter {
@@ -126,44 +548,270 @@ impl ResCounter {
             score_too_low: 0,
             left_too_short: 0,
             right_too_short: 0,
+            too_many_n: 0,
+            low_quality: 0,
+            trimmed_too_short: 0,
+            concatemer: 0,
+            concatemer_pairs: 0,
+            rescued_by_r2: 0,
+            input_reads: 0,
         }
     }
 
-    fn count(&mut self, res: &ExtractRes) {
+    fn count(&mut self, res: &ExtractRes, rescued: bool) {
         match res{
-            ExtractRes::Ok(_, _) =>{ self.linker_reads += 1 },
+            ExtractRes::Ok(_, _) =>{
+                self.linker_reads += 1;
+                if rescued { self.rescued_by_r2 += 1 }
+            },
             ExtractRes::ScoreTooLow =>{ self.score_too_low += 1 },
             ExtractRes::LeftTooShort =>{ self.left_too_short += 1 },
-            ExtractRes::RightTooShort =>{ self.right_too_short += 1 },
+            ExtractRes::RightTooShort(_) =>{ self.right_too_short += 1 },
+            ExtractRes::TooManyN =>{ self.too_many_n += 1 },
+            ExtractRes::LowQuality =>{ self.low_quality += 1 },
+            ExtractRes::TrimmedTooShort =>{ self.trimmed_too_short += 1 },
+            ExtractRes::Concatemer(pairs) =>{
+                self.concatemer += 1;
+                self.concatemer_pairs += pairs.len() as u64;
+            },
         }
     }
+
+    fn total(&self) -> u64 {
+        self.linker_reads + self.score_too_low +
+        self.left_too_short + self.right_too_short +
+        self.too_many_n + self.low_quality + self.trimmed_too_short +
+        self.concatemer
+    }
+
+    fn merge(&mut self, other: &ResCounter) {
+        self.linker_reads += other.linker_reads;
+        self.score_too_low += other.score_too_low;
+        self.left_too_short += other.left_too_short;
+        self.right_too_short += other.right_too_short;
+        self.too_many_n += other.too_many_n;
+        self.low_quality += other.low_quality;
+        self.trimmed_too_short += other.trimmed_too_short;
+        self.concatemer += other.concatemer;
+        self.concatemer_pairs += other.concatemer_pairs;
+        self.rescued_by_r2 += other.rescued_by_r2;
+    }
 }
 
 impl fmt::Display for ResCounter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let total = self.linker_reads + self.score_too_low +
-                    self.left_too_short + self.right_too_short;
+        let total = self.total();
         let ratio = |c| {
-            if total == 0 { return format!("0%"); }
+            if total == 0 { return "0%".to_string(); }
             format!("{:.2}%", ((c*100) as f64) / (total as f64))
         };
         write!(f,
             "Count result:
     linker reads\t{}\t{}
+      rescued by R2\t{}\t{}
     score too low\t{}\t{}
     left too short\t{}\t{}
     right too short\t{}\t{}
-total reads: {}\n",
+    too many N\t{}\t{}
+    low quality\t{}\t{}
+    concatemer\t{}\t{}
+    too short after trim\t{}\t{}
+total reads: {}
+input reads: {}\n",
             self.linker_reads, ratio(self.linker_reads),
+            self.rescued_by_r2, ratio(self.rescued_by_r2),
             self.score_too_low, ratio(self.score_too_low),
             self.left_too_short, ratio(self.left_too_short),
             self.right_too_short, ratio(self.right_too_short),
-            total,
+            self.too_many_n, ratio(self.too_many_n),
+            self.low_quality, ratio(self.low_quality),
+            self.concatemer, ratio(self.concatemer),
+            self.trimmed_too_short, ratio(self.trimmed_too_short),
+            total, self.input_reads,
         )
     }
 }
 
 
+type ReadPair = (fastq::Record, Option<fastq::Record>);
+type ReadPairs = Box<dyn Iterator<Item=ReadPair> + Send>;
+
+// number of read pairs sent to worker threads at once
+const CHUNK_SIZE: usize = 4096;
+
+
+/// Pair counts accumulated in each worker thread, merged after all reads processed.
+struct PairCounts {
+    freq: PairFreq,
+    qual_sums: HashMap<SeqKey, QualSum>,
+    counter: ResCounter,
+    merge_counter: MergeCounter,
+    trim_counter: TrimCounter,
+    prefilter_check: PrefilterCheck,
+}
+
+impl PairCounts {
+    fn new() -> Self {
+        Self {
+            freq: HashMap::new(),
+            qual_sums: HashMap::new(),
+            counter: ResCounter::new(),
+            merge_counter: MergeCounter::new(),
+            trim_counter: TrimCounter::new(),
+            prefilter_check: PrefilterCheck::new(),
+        }
+    }
+
+    fn add_pair(&mut self, left: &Flank, right: &Flank, reverse: bool, oriented: bool, var_len: bool) {
+        let ((k0, q0), (k1, q1), strand) = pair_key(left, right, reverse, oriented, var_len);
+        self.qual_sums.entry(k0.clone()).or_insert_with(QualSum::new).add(&q0);
+        self.qual_sums.entry(k1.clone()).or_insert_with(QualSum::new).add(&q1);
+        *self.freq.entry((k0, k1, strand)).or_insert(0) += 1;
+    }
+
+    fn merge(&mut self, other: PairCounts) {
+        for (k, v) in other.freq {
+            *self.freq.entry(k).or_insert(0) += v;
+        }
+        for (k, q) in other.qual_sums {
+            self.qual_sums.entry(k).or_insert_with(QualSum::new).merge(&q, false);
+        }
+        self.counter.merge(&other.counter);
+        self.merge_counter.merge(&other.merge_counter);
+        self.trim_counter.merge(&other.trim_counter);
+        self.prefilter_check.merge(&other.prefilter_check);
+    }
+}
+
+
+/// Search linker in a read pair (or single read), the mates are trimmed first if `trimmer`
+/// is given, then merged if `merge` (min overlap, max mismatch ratio) is given.
+fn process_pair(
+        rec: &fastq::Record, rec2: Option<&fastq::Record>,
+        linkers: &Linkers, params: &ExtractParams,
+        trimmer: Option<&Trimmer>, trim_counter: &mut TrimCounter,
+        merge: Option<(usize, f32)>) -> (Vec<(ExtractRes, Alignment)>, bool, Option<usize>) {
+    let mut trim = |seq: &[u8], mate: usize| match trimmer {
+        Some(t) => t.trim(seq, mate, trim_counter),
+        None => Some(seq.len()),
+    };
+    let len1 = trim(rec.seq(), 0);
+    let len2 = rec2.map(|r| trim(r.seq(), 1));
+    let search = |rec: &fastq::Record, len: Option<usize>| match len {
+        Some(l) => search_read(&rec.seq()[..l], &rec.qual()[..l], linkers, params),
+        None => vec![(ExtractRes::TrimmedTooShort, Alignment::default())],
+    };
+
+    let merged = match (rec2, len1, len2.flatten(), merge) {
+        (Some(rec2), Some(l1), Some(l2), Some((min_overlap, max_mismatch))) => merge_mates(
+            &rec.seq()[..l1], &rec.qual()[..l1], &rec2.seq()[..l2], &rec2.qual()[..l2],
+            min_overlap, max_mismatch),
+        _ => None,
+    };
+    let overlap = merged.as_ref().map(|m| m.overlap);
+    let merged_res = merged.map(|m| search_read(&m.seq, &m.qual, linkers, params));
+    let (align_res, rescued) = match (merged_res, rec2) {
+        (Some(res), _) if matches!(res[res.len()-1].0, ExtractRes::Ok(_, _) | ExtractRes::Concatemer(_)) => (res, false),
+        // fall back to search the mates separately
+        (_, Some(rec2)) => {
+            let align_res = search(rec, len1);
+            let align_res2 = search(rec2, len2.flatten());
+            combine_mates(align_res, align_res2, params)
+        },
+        (_, None) => (search(rec, len1), false),
+    };
+    (align_res, rescued, overlap)
+}
+
+
+/// Options of writing the pair counts, shared by all samples.
+struct OutputOpts {
+    flanking: usize,
+    cluster_dist: usize,
+    oriented: bool,
+}
+
+
+/// Correct the flanks by clustering if enabled, then write the pair counts and sequences.
+fn write_counts(mut freq: PairFreq, mut qual_sums: HashMap<SeqKey, QualSum>, out_prefix: &str, opts: &OutputOpts) {
+    let OutputOpts { flanking, cluster_dist, oriented } = *opts;
+
+    if cluster_dist > 0 {
+        let mut key_counts: HashMap<SeqKey, u64> = HashMap::new();
+        for ((k0, k1, _), v) in &freq {
+            *key_counts.entry(k0.clone()).or_insert(0) += v;
+            *key_counts.entry(k1.clone()).or_insert(0) += v;
+        }
+        let corrections = directional_cluster(&key_counts, flanking, cluster_dist, !oriented);
+        info!("{} of {} kinds of sequences were corrected by clustering.", corrections.len(), key_counts.len());
+
+        let map_path = format!("{}.cnt.map", out_prefix);
+        info!("Write corrected sequences to tsv file: {}", map_path);
+        let mut map_file = File::create(map_path).unwrap();
+        let mut corr_vec: Vec<(&SeqKey, &Correction)> = corrections.iter().collect();
+        corr_vec.sort_by(|a, b| a.1.parent.cmp(&b.1.parent).then(key_counts[b.0].cmp(&key_counts[a.0])));
+        for (k, c) in corr_vec {
+            let _ = writeln!(map_file, "{}\t{}\t{}\t{}\t{}",
+                k, c.parent, c.distance, key_counts[k], key_counts[&c.parent]);
+        }
+
+        let corrected = correct_pairs(freq, qual_sums, &corrections, oriented);
+        freq = corrected.0;
+        qual_sums = corrected.1;
+    }
+
+    let cnt_path = format!("{}.cnt", out_prefix);
+    let fq_out_path = format!("{}.cnt.fq", out_prefix);
+    let mut cnt_file = File::create(cnt_path.clone()).unwrap();
+    let fq_out_file = File::create(fq_out_path.clone()).unwrap();
+    let mut fq_out = fastq::Writer::new(fq_out_file);
+
+    let mut kv_vec = vec![];
+    for (k, v) in &freq {
+        kv_vec.push((&k.0, &k.1, &k.2, v));
+    }
+    info!("Totally {} kinds of pairs and {} kinds of sequences were founded.", freq.len(), qual_sums.len());
+    kv_vec.sort_by(|a, b| b.3.cmp(a.3));
+    info!("Write pair counts to tsv file: {}", cnt_path);
+    for (k0, k1, strand, v) in kv_vec {
+        if oriented {
+            let _ = writeln!(cnt_file, "{}\t{}\t{}\t{}", k0, k1, v, strand);
+        } else {
+            let _ = writeln!(cnt_file, "{}\t{}\t{}", k0, k1, v);
+        }
+    }
+
+    info!("Write sequences to fastq file: {}", fq_out_path);
+    let mut key_vec = qual_sums.iter().collect::<Vec<(&SeqKey, &QualSum)>>();
+    key_vec.sort_by(|a, b| a.0.cmp(b.0));
+    for (k, qual_sum) in key_vec {
+        let seq = recover_seq(k, flanking);
+        let id = format!("{}", k);
+        let qual = qual_sum.mean();
+        let _ = fq_out.write(
+            &id,
+            Option::None,
+            seq.as_bytes(),
+            &qual,
+        );
+    }
+}
+
+
+fn open_fq(path: &str) -> impl Iterator<Item=fastq::Record> + Send {
+    let fq_file: Box<dyn Read + Send + Sync> = if path.ends_with(".gz") {
+        Box::new(GzDecoder::new(File::open(path).unwrap()))
+    } else {
+        Box::new(File::open(path).unwrap())
+    };
+    let fq = fastq::Reader::new(fq_file);
+    fq.records().map(|r| match r {
+        Ok(r_) => r_,
+        Err(e) => panic!("{:?}", e),
+    })
+}
+
+
 fn main() {
     simple_logger::init().unwrap();
 
@@ -171,16 +819,16 @@ fn main() {
         .arg(Arg::with_name("fq")
              .required(true)
              .help("Fastq file of reads 1."))
+        .arg(Arg::with_name("fq2")
+             .help("Fastq file of reads 2, enable the paired-end mode."))
         .arg(Arg::with_name("linker")
              .short("l")
              .long("linker")
-             .required(true)
              .takes_value(true)
              .help("The linker sequence(Not incluede enzyme)."))
         .arg(Arg::with_name("enzyme")
              .short("e")
              .long("enzyme")
-             .required(true)
              .takes_value(true)
              .help("Enzyme recognize site.")
             )
@@ -195,11 +843,110 @@ fn main() {
              .long("flanking")
              .takes_value(true)
              .help("Flanking length."))
+        .arg(Arg::with_name("structure")
+             .long("structure")
+             .takes_value(true)
+             .help("Read structure, like '13F 6E 54L 6E 13F'. F: flank, E: enzyme site, L: linker, B/U/N: barcode/UMI/spacer (skipped, searched as wildcards inside the core), or literal sequence. Flanks can have different lengths. Default is built from --flanking."))
+        .arg(Arg::with_name("max_flanking")
+             .long("max_flanking")
+             .takes_value(true)
+             .help("Extract variable-length flanks up to this length, stop before next enzyme site or adapter. The --flanking becomes the minimal length."))
+        .arg(Arg::with_name("adapter")
+             .long("adapter")
+             .takes_value(true)
+             .help("Adapter sequence at 3' of reads (R1) for trimming, variable-length flanks also stop before it. Default is the TruSeq adapter."))
+        .arg(Arg::with_name("adapter2")
+             .long("adapter2")
+             .takes_value(true)
+             .help("Adapter sequence at 3' of R2 for trimming and the boundary of variable-length flanks, default is the TruSeq adapter."))
+        .arg(Arg::with_name("trim")
+             .long("trim")
+             .help("Trim poly-G tails and adapters at 3' of reads before linker searching."))
+        .arg(Arg::with_name("min_read_len")
+             .long("min_read_len")
+             .takes_value(true)
+             .help("Reads shorter than it after trimming are discarded, default is the linker pattern length."))
+        .arg(Arg::with_name("split_concatemers")
+             .long("split_concatemers")
+             .help("Count the flank pairs of every linker in concatemer reads (with multiple linkers), instead of discarding them."))
+        .arg(Arg::with_name("max_n")
+             .long("max_n")
+             .takes_value(true)
+             .help("Max number of ambiguous bases(N) allowed in each flank, pairs with more N are discarded."))
+        .arg(Arg::with_name("oriented")
+             .long("oriented")
+             .help("Record the oriented (left, right) pairs and strand, left flank is on the bait side of linker."))
         .arg(Arg::with_name("score_ratio_thresh")
              .short("s")
              .long("score_ratio_thresh")
              .takes_value(true)
-             .help("Threshold of (align score / pattern length)"))
+             .help("Threshold of (align score / max score of pattern), max score is pattern length * match score."))
+        .arg(Arg::with_name("max_edit_dist")
+             .long("max_edit_dist")
+             .takes_value(true)
+             .help("Max number of edits (mismatches and gaps) in linker alignment, use it instead of the score ratio."))
+        .arg(Arg::with_name("match_score")
+             .long("match_score")
+             .takes_value(true)
+             .help("Score of matched bases in linker alignment."))
+        .arg(Arg::with_name("mismatch_score")
+             .long("mismatch_score")
+             .takes_value(true)
+             .allow_hyphen_values(true)
+             .help("Score of mismatched bases in linker alignment, negative value."))
+        .arg(Arg::with_name("gap_open")
+             .long("gap_open")
+             .takes_value(true)
+             .allow_hyphen_values(true)
+             .help("Gap open score in linker alignment, negative value."))
+        .arg(Arg::with_name("gap_extend")
+             .long("gap_extend")
+             .takes_value(true)
+             .allow_hyphen_values(true)
+             .help("Gap extend score in linker alignment, negative value."))
+        .arg(Arg::with_name("calibrate")
+             .long("calibrate")
+             .takes_value(true)
+             .help("Calibrate the score threshold on this number of reads with shuffled decoy linkers, 0 for disabled."))
+        .arg(Arg::with_name("calibrate_fpr")
+             .long("calibrate_fpr")
+             .takes_value(true)
+             .help("Max estimated false positive rate of the recommended score threshold."))
+        .arg(Arg::with_name("auto_thresh")
+             .long("auto_thresh")
+             .help("Apply the recommended score threshold of calibration."))
+        .arg(Arg::with_name("min_base_qual")
+             .long("min_base_qual")
+             .takes_value(true)
+             .help("Minimal base quality of flanks."))
+        .arg(Arg::with_name("min_mean_qual")
+             .long("min_mean_qual")
+             .takes_value(true)
+             .help("Minimal mean base quality of flanks."))
+        .arg(Arg::with_name("max_expected_errors")
+             .long("max_expected_errors")
+             .takes_value(true)
+             .help("Max expected errors (sum of error probabilities) of flanks."))
+        .arg(Arg::with_name("no_prefilter")
+             .long("no_prefilter")
+             .help("Run the full alignment on every read, without locating linker by bit-parallel edit distance search first."))
+        .arg(Arg::with_name("check_prefilter")
+             .long("check_prefilter")
+             .takes_value(true)
+             .help("Compare the prefilter result with full alignment on every N reads, 0 for disabled."))
+        .arg(Arg::with_name("cluster_dist")
+             .long("cluster_dist")
+             .takes_value(true)
+             .possible_values(&["0", "1", "2"])
+             .help("Correct sequencing errors by clustering flanks within this hamming distance(1 or 2) to high-abundance flanks, 0 for disabled."))
+        .arg(Arg::with_name("samples")
+             .long("samples")
+             .takes_value(true)
+             .help("Sample sheet (TSV of sample name and index sequence), demultiplex reads by the index in R1 header and output counts of each sample to <prefix>.<sample>."))
+        .arg(Arg::with_name("index_mismatch")
+             .long("index_mismatch")
+             .takes_value(true)
+             .help("Max mismatches between the read index and sample index."))
         .arg(Arg::with_name("align_detail")
              .short("d")
              .long("detail")
@@ -210,170 +957,574 @@ fn main() {
              .long("threads")
              .takes_value(true)
              .help("Number of threads used for processing reads."))
-        .arg(Arg::with_name("wait_timeout")
-             .long("wait_timeout")
+        .arg(Arg::with_name("merge")
+             .long("merge")
+             .help("Merge overlapped R1 and R2 before linker searching, only for paired-end mode."))
+        .arg(Arg::with_name("merge_min_overlap")
+             .long("merge_min_overlap")
+             .takes_value(true)
+             .help("Minimal overlap length for merging mates."))
+        .arg(Arg::with_name("merge_max_mismatch")
+             .long("merge_max_mismatch")
              .takes_value(true)
-             .help("Wait time for end channel timeout."))
+             .help("Max ratio of mismatches in the overlap region for merging mates."))
         .get_matches();
 
     let fq_path = matches.value_of("fq").unwrap();
+    let fq2_path = matches.value_of("fq2");
     let out_prefix = matches.value_of("output_prefix").unwrap();
-    let linker = matches.value_of("linker").unwrap();
+    let linker = matches.value_of("linker");
     let enzyme = matches.value_of("enzyme").unwrap_or("GTTGGA");
-    let flanking = matches.value_of("flanking").unwrap_or("13");
-    let flanking: u8 = flanking.parse().unwrap();
+    let flanking: Option<usize> = matches.value_of("flanking").map(|f| f.parse().unwrap());
+    let spec = match matches.value_of("structure") {
+        Some(s) => s.to_string(),
+        None => ReadStructure::default_spec(flanking.unwrap_or(13)),
+    };
+    let structure = match ReadStructure::parse(&spec, enzyme.as_bytes(), linker.map(|l| l.as_bytes())) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Invalid read structure '{}': {}", spec, e);
+            process::exit(1)
+        },
+    };
+    info!("Read structure: {}", spec);
+    if linker.is_some() && !structure.uses_linker {
+        warn!("The read structure has no linker segment (L), --linker is ignored.");
+    }
+    let (left_len, right_len) = structure.flank_lens;
+    if let Some(f) = flanking {
+        if f != left_len || f != right_len {
+            error!("--flanking {} disagrees with the flank lengths ({} and {}) of the read structure.", f, left_len, right_len);
+            process::exit(1)
+        }
+    }
+    let flanking = left_len.min(right_len);
+    let max_flanking: Option<usize> = matches.value_of("max_flanking").map(|m| m.parse().unwrap());
+    // flanks of different lengths are recorded with their lengths, as the variable-length flanks
+    let var_len = max_flanking.is_some() || left_len != right_len;
+    let adapter = matches.value_of("adapter").unwrap_or(ADAPTER_R1);
+    let adapter2 = matches.value_of("adapter2").unwrap_or(ADAPTER_R2);
+    let trim = matches.is_present("trim");
+    if let Some(m) = max_flanking {
+        if m < left_len.max(right_len) {
+            error!("--max_flanking {} is shorter than the flanks ({} and {}) of the read structure.", m, left_len, right_len);
+            process::exit(1)
+        }
+        info!("Extract variable-length flanks of {} to {} bases.", flanking, m);
+    }
     let score_ratio_thresh = matches.value_of("score_ratio_thresh").unwrap_or("0.6");
     let score_ratio_thresh: f32 = score_ratio_thresh.parse().unwrap();
+    let mut params = ExtractParams {
+        score_ratio_thresh,
+        max_edit_dist: matches.value_of("max_edit_dist").map(|d| d.parse().unwrap()),
+        match_score: matches.value_of("match_score").unwrap_or("1").parse().unwrap(),
+        mismatch_score: matches.value_of("mismatch_score").unwrap_or("-1").parse().unwrap(),
+        gap_open: matches.value_of("gap_open").unwrap_or("-1").parse().unwrap(),
+        gap_extend: matches.value_of("gap_extend").unwrap_or("-1").parse().unwrap(),
+        max_n: matches.value_of("max_n").unwrap_or("0").parse().unwrap(),
+        min_base_qual: matches.value_of("min_base_qual").unwrap_or("0").parse().unwrap(),
+        min_mean_qual: matches.value_of("min_mean_qual").unwrap_or("0").parse().unwrap(),
+        max_expected_errors: matches.value_of("max_expected_errors").unwrap_or("inf").parse().unwrap(),
+    };
+    let oriented = matches.is_present("oriented");
+    let split_concatemers = matches.is_present("split_concatemers");
+    let calibrate_n: usize = matches.value_of("calibrate").unwrap_or("0").parse().unwrap();
+    let calibrate_fpr: f64 = matches.value_of("calibrate_fpr").unwrap_or("0.001").parse().unwrap();
+    let use_prefilter = !matches.is_present("no_prefilter");
+    let check_prefilter: u64 = matches.value_of("check_prefilter").unwrap_or("0").parse().unwrap();
+    let cluster_dist: usize = matches.value_of("cluster_dist").unwrap_or("0").parse().unwrap();
+    let index_mismatch: usize = matches.value_of("index_mismatch").unwrap_or("1").parse().unwrap();
     let threads = matches.value_of("threads").unwrap_or("1");
     let threads: u8 = threads.parse().unwrap();
-    let wait_t = matches.value_of("wait_timeout").unwrap_or("500");
-    let wait_t: u64 = wait_t.parse().unwrap();
+    let merge = matches.is_present("merge") && fq2_path.is_some();
+    let merge_min_overlap: usize = matches.value_of("merge_min_overlap").unwrap_or("30").parse().unwrap();
+    let merge_max_mismatch: f32 = matches.value_of("merge_max_mismatch").unwrap_or("0.1").parse().unwrap();
 
-    let mut detail_file = match matches.value_of("align_detail") {
-        Some(p) => Some(File::create(p).unwrap()),
-        None => None,
-    };
+    let detail_file = matches.value_of("align_detail").map(|p| File::create(p).unwrap());
 
-    let fq_file: Box<dyn Read + Send + Sync> = if fq_path.ends_with(".gz") {
-        Box::new(GzDecoder::new(File::open(fq_path).unwrap()))
-    } else {
-        Box::new(File::open(fq_path).unwrap())
+    let mut records: ReadPairs = match fq2_path {
+        None => Box::new(open_fq(fq_path).map(|r| (r, None))),
+        Some(fq2_path) => {
+            info!("Run in paired-end mode.");
+            let mut fq1 = open_fq(fq_path);
+            let mut fq2 = open_fq(fq2_path);
+            // not zip, which silently drops the extra reads of the longer file
+            Box::new(std::iter::from_fn(move || match (fq1.next(), fq2.next()) {
+                (Some(r1), Some(r2)) => {
+                    if mate_name(r1.id()) != mate_name(r2.id()) {
+                        panic!("Read ids of R1 and R2 not match: {} {}", r1.id(), r2.id());
+                    }
+                    Some((r1, Some(r2)))
+                },
+                (None, None) => None,
+                (Some(r1), None) => panic!("R2 file ended before R1 read: {}", r1.id()),
+                (None, Some(r2)) => panic!("R1 file ended before R2 read: {}", r2.id()),
+            }))
+        },
     };
-    let fq = fastq::Reader::new(fq_file);
-    let records = fq.records();
-
-    let mut freq: HashMap<(u64, u64), u64> = HashMap::new();
-
-    let l_vec = linker.as_bytes().to_vec();
-    let e_vec = enzyme.as_bytes().to_vec();
-    let e_rc = revcomp(&e_vec);
-    let l_rc = revcomp(&l_vec);
-    let patterns = [
-        [&e_vec[..], &l_vec[..], &e_rc].concat(),
-        [&e_vec[..], &l_rc[..],  &e_rc].concat(),
-    ];
+
+    let patterns = structure.patterns();
     info!("patterns:\n    {}\n    {}",
         str::from_utf8(&patterns[0]).unwrap(),
         str::from_utf8(&patterns[1]).unwrap(),
     );
 
-    let mut counter = ResCounter::new();
-    let records = Arc::new(Mutex::new(records));
-    let patterns = Arc::new(patterns);
-    let mut handles = vec![];
-    let (tx, rx) = mpsc::channel();
+    if calibrate_n > 0 {
+        let sample: Vec<ReadPair> = records.by_ref().take(calibrate_n).collect();
+        let seqs: Vec<&[u8]> = sample.iter()
+            .flat_map(|(r1, r2)| std::iter::once(r1.seq()).chain(r2.as_ref().map(|r| r.seq())))
+            .collect();
+        info!("Calibrate score threshold with {} sequences.", seqs.len());
+        let decoys = decoy_patterns(&patterns);
+        let hist = ScoreHist::new(&seqs, &patterns, &decoys, &params);
+        let hist_path = format!("{}.calib.tsv", out_prefix);
+        info!("Write score distributions to tsv file: {}", hist_path);
+        hist.write_tsv(&hist_path);
+
+        let pattern_len = patterns.iter().map(|p| p.len()).max().unwrap();
+        let (pass, fpr) = hist.rates(params.min_score(pattern_len));
+        info!("Current threshold {}: {:.2}% reads pass, estimated false positive rate {:.4}%",
+            params.score_ratio_thresh, pass * 100.0, fpr * 100.0);
+        let score = hist.recommend(calibrate_fpr);
+        // integer scores, the threshold between score - 1 and score
+        let ratio = hist.score_ratio(score) - 0.5 / (pattern_len as i32 * params.match_score) as f32;
+        let (pass, fpr) = hist.rates(score as f32);
+        info!("Recommended threshold {:.4} (score >= {}): {:.2}% reads pass, estimated false positive rate {:.4}%",
+            ratio, score, pass * 100.0, fpr * 100.0);
+        if matches.is_present("auto_thresh") {
+            if params.max_edit_dist.is_some() {
+                warn!("Linker is found by edit distance, the recommended score threshold is not applied.");
+            } else {
+                info!("Apply the recommended threshold.");
+                params.score_ratio_thresh = ratio;
+            }
+        }
+        records = Box::new(sample.into_iter().chain(records));
+    }
 
     info!("Run with {} threads.", threads);
 
+    let sheet = matches.value_of("samples").map(|p| {
+        let sheet = match SampleSheet::from_tsv(p, index_mismatch) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Invalid sample sheet: {}", e);
+                process::exit(1)
+            },
+        };
+        info!("Demultiplex {} samples, with at most {} mismatches in index.", sheet.names.len() - 1, index_mismatch);
+        Arc::new(sheet)
+    });
+    // the undetermined reads are in the last sample
+    let n_samples = sheet.as_ref().map(|s| s.names.len()).unwrap_or(1);
+
+    // reader thread, decompress and parse reads, demultiplex them by the index in R1 header,
+    // and send them to workers in chunks
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Vec<(usize, ReadPair)>>(threads as usize * 2);
+    // the channel is closed when reader finished, then workers exit after draining it
+    let reader_sheet = sheet.clone();
+    let reader = thread::spawn(move || {
+        // input reads of each sample
+        let mut n_reads: Vec<u64> = vec![0; n_samples];
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        for r in records {
+            let sample = reader_sheet.as_ref().map(|s| s.assign(header_index(r.0.desc()))).unwrap_or(0);
+            n_reads[sample] += 1;
+            chunk.push((sample, r));
+            if chunk.len() == CHUNK_SIZE {
+                chunk_tx.send(chunk).unwrap();
+                chunk = Vec::with_capacity(CHUNK_SIZE);
+            }
+        }
+        if !chunk.is_empty() { chunk_tx.send(chunk).unwrap() }
+        n_reads
+    });
+
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    info!("Alignment scores: match {}, mismatch {}, gap open {}, gap extend {}",
+        params.match_score, params.mismatch_score, params.gap_open, params.gap_extend);
+    for p in &patterns {
+        match params.max_edit_dist {
+            Some(d) => info!("Linker found with at most {} edits, pattern length {}.", d, p.len()),
+            None => info!("Linker found with alignment score >= {:.1}, pattern length {}.", params.min_score(p.len()), p.len()),
+        }
+    }
+    let max_edits: Option<Vec<usize>> = patterns.iter().map(|p| params.max_edits(p.len())).collect();
+    let use_prefilter = match max_edits {
+        None if use_prefilter => {
+            warn!("Number of edits can't be bounded by the scoring scheme, prefilter is disabled.");
+            false
+        },
+        _ => use_prefilter,
+    };
+    let prefilter = match max_edits {
+        // in edit distance mode, the best scored alignment may out of the window
+        Some(d) if use_prefilter => Some(Prefilter::new(&patterns, d, params.max_edit_dist.is_none())),
+        _ => None,
+    };
+    let trimmer = if trim {
+        let min_len = matches.value_of("min_read_len").map(|l| l.parse().unwrap())
+            .unwrap_or_else(|| patterns.iter().map(|p| p.len()).min().unwrap());
+        info!("Trim adapter and poly-G, reads shorter than {} after trimming are discarded.", min_len);
+        Some(Arc::new(Trimmer::new(adapter.as_bytes(), adapter2.as_bytes(), min_len)))
+    } else {
+        None
+    };
+    let boundary = || max_flanking.map(|m| FlankBoundary::new(m, enzyme.as_bytes(), &[adapter.as_bytes(), adapter2.as_bytes()]));
+    let full_linkers = Arc::new(Linkers { layouts: structure.layouts.clone(), boundary: boundary(), prefilter: None });
+    let linkers = Arc::new(Linkers { layouts: structure.layouts, boundary: boundary(), prefilter });
+    let detail_file = detail_file.map(|f| Arc::new(Mutex::new(f)));
+    let merge_opt = if merge { Some((merge_min_overlap, merge_max_mismatch)) } else { None };
+    let mut handles = vec![];
+
     for _ in 0..threads {
-        let records = Arc::clone(&records);
-        let patterns = Arc::clone(&patterns);
-        let tx1 = mpsc::Sender::clone(&tx);
+        let chunk_rx = Arc::clone(&chunk_rx);
+        let linkers = Arc::clone(&linkers);
+        let full_linkers = Arc::clone(&full_linkers);
+        let detail_file = detail_file.clone();
+        let trimmer = trimmer.clone();
 
         let handle = thread::spawn(move || {
+            let mut samples: Vec<PairCounts> = (0..n_samples).map(|_| PairCounts::new()).collect();
+            let mut n_seen: u64 = 0;
             loop {
-                // read seq from fq file
-                let rec = {
-                    let mut records = records.lock().unwrap();
-                    match records.next() {
-                        Some(r) => match r {
-                            Ok(r_) => r_,
-                            Err(e) => panic!("{:?}", e),
-                        },
-                        None => break
-                    }
+                let chunk = match chunk_rx.lock().unwrap().recv() {
+                    Ok(c) => c,
+                    Err(_) => break,  // reader finished
                 };
-                let seq = String::from_utf8(rec.seq().to_vec()).unwrap();
-
-                let mut align_res: Vec<(ExtractRes, Alignment)> = Vec::with_capacity(2);
-                for pattern in patterns.iter() {
-                    align_res.push(extract_pet(seq.as_bytes(), &pattern, flanking, score_ratio_thresh));
-                    let res = &align_res[align_res.len()-1].0;
-                    match res {
-                        ExtractRes::Ok(_, _) => {
-                            break
+                let mut detail = String::new();
+                for (sample, (rec, rec2)) in chunk {
+                    let counts = &mut samples[sample];
+                    let (align_res, rescued, overlap) = process_pair(
+                        &rec, rec2.as_ref(), &linkers, &params,
+                        trimmer.as_deref(), &mut counts.trim_counter, merge_opt);
+                    if use_prefilter && check_prefilter > 0 {
+                        if n_seen.is_multiple_of(check_prefilter) {
+                            let full_res = process_pair(
+                                &rec, rec2.as_ref(), &full_linkers, &params,
+                                trimmer.as_deref(), &mut TrimCounter::new(), merge_opt);
+                            counts.prefilter_check.checked += 1;
+                            if !same_result(&align_res, &full_res.0) {
+                                counts.prefilter_check.discordant += 1;
+                                warn!("Prefilter result differs from full alignment: {}", rec.id());
+                            }
+                        }
+                        n_seen += 1;
+                    }
+                    let res = &align_res[align_res.len()-1];
+                    if detail_file.is_some() {
+                        let alignment = &res.1;
+                        detail.push_str(&format!("{}\t{}\t{}\t{}\t{}\n",
+                            rec.id(), align_res.len(),
+                            alignment.score, alignment.ystart, alignment.yend));
+                    }
+
+                    // count left-right pair, the reversed linker pattern means read is on reverse strand
+                    match &res.0 {
+                        ExtractRes::Ok(left, right) => {
+                            counts.add_pair(left, right, align_res.len() == 2, oriented, var_len);
                         },
-                        _ => {
-                            continue    
+                        ExtractRes::Concatemer(pairs) if split_concatemers => {
+                            for (left, right, reverse) in pairs {
+                                counts.add_pair(left, right, *reverse, oriented, var_len);
+                            }
                         },
+                        _ => {},
                     }
+                    counts.counter.count(&res.0, rescued);
+                    if merge { counts.merge_counter.count(overlap) }
+                }
+                if let Some(f) = &detail_file {
+                    let _ = f.lock().unwrap().write_all(detail.as_bytes());
                 }
-                let rec_id = String::from(rec.id());
-                tx1.send((align_res, rec_id)).unwrap();
             }
+            samples
         });
         handles.push(handle);
     }
 
-    loop {
-        match rx.recv_timeout(Duration::from_millis(wait_t)) {
-            Ok((align_res, rec_id)) => {
-                let res = &align_res[align_res.len()-1];
-                let alignment = &res.1;
-                if let Some(mut f) = detail_file {
-                    // write align detail
-                    let _ = writeln!(f,
-                        "{}\t{}\t{}\t{}\t{}",
-                        rec_id, align_res.len(),
-                        alignment.score, alignment.ystart, alignment.yend,
-                    );
-                    detail_file = Some(f);
-                }
-
-                // count left-right pair
-                if let ExtractRes::Ok(left, right) = &res.0 {
-                    let mut key: (u64, u64) = (compress_seq(left.as_bytes()).unwrap(),
-                                               compress_seq(right.as_bytes()).unwrap());
-                    if key.0 > key.1 { mem::swap(&mut key.0, &mut key.1) };
-                    *freq.entry(key).or_insert(0) += 1;
-                }
-                counter.count(&res.0);
+    let n_input = reader.join().unwrap();
+    let mut samples: Vec<PairCounts> = (0..n_samples).map(|_| PairCounts::new()).collect();
+    for handle in handles {  // wait all threads fishish, merge their counts
+        for (counts, other) in samples.iter_mut().zip(handle.join().unwrap()) {
+            counts.merge(other);
+        }
+    }
+    info!("End processing.");
+    for (i, (counts, n)) in samples.iter().zip(&n_input).enumerate() {
+        let n_processed = counts.counter.total();
+        if n_processed != *n {
+            let name = sheet.as_ref().map(|s| format!(" of sample {}", s.names[i])).unwrap_or_default();
+            error!("Number of processed reads {} not equal to input reads {}{}.", n_processed, n, name);
+            process::exit(1)
+        }
+    }
+    if let Some(sheet) = &sheet {
+        info!("{}", DemuxSummary { names: &sheet.names, reads: n_input.clone() });
+    }
+    let opts = OutputOpts { flanking, cluster_dist, oriented };
+    let mut prefilter_check = PrefilterCheck::new();
+    for (i, counts) in samples.into_iter().enumerate() {
+        let PairCounts { freq, qual_sums, mut counter, merge_counter, trim_counter, prefilter_check: check } = counts;
+        prefilter_check.merge(&check);
+        counter.input_reads = n_input[i];
+        let prefix = match &sheet {
+            Some(sheet) => {
+                info!("Sample: {}", sheet.names[i]);
+                format!("{}.{}", out_prefix, sheet.names[i])
             },
-            _ => {
-                info!("End processing.");
-                break;
-            }
+            None => out_prefix.to_string(),
+        };
+        write_counts(freq, qual_sums, &prefix, &opts);
+
+        info!("{}", counter);
+        if counter.concatemer > 0 {
+            let action = if split_concatemers { "counted" } else { "discarded" };
+            info!("{} flank pairs in {} concatemer reads were {}.", counter.concatemer_pairs, counter.concatemer, action);
+        }
+        if trim {
+            info!("{}", trim_counter);
+        }
+        if merge {
+            info!("{}", merge_counter);
         }
     }
+    if prefilter_check.checked > 0 {
+        info!("Prefilter check: {} of {} reads differ from full alignment.",
+            prefilter_check.discordant, prefilter_check.checked);
+    }
+}
+
 
-    for handle in handles {  // wait all threads fishish
-        handle.join().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ExtractParams {
+        ExtractParams {
+            score_ratio_thresh: 0.6, max_edit_dist: None,
+            match_score: 1, mismatch_score: -1, gap_open: -1, gap_extend: -1,
+            max_n: 0, min_base_qual: 0, min_mean_qual: 0.0, max_expected_errors: f32::INFINITY,
+        }
     }
 
-    let cnt_path = format!("{}.cnt", out_prefix);
-    let fq_out_path = format!("{}.cnt.fq", out_prefix);
-    let mut cnt_file = File::create(cnt_path.clone()).unwrap();
-    let fq_out_file = File::create(fq_out_path.clone()).unwrap();
-    let mut fq_out = fastq::Writer::new(fq_out_file);
+    fn flank(seq: &[u8]) -> Flank {
+        Flank::new(seq, &vec![b'I'; seq.len()])
+    }
 
-    let mut key_set = HashSet::new();
-    let mut kv_vec = vec![];
-    for (k, v) in &freq {
-        key_set.insert(k.0);
-        key_set.insert(k.1);
-        kv_vec.push((k.0, k.1, v));
+    fn res(r: ExtractRes) -> Vec<(ExtractRes, Alignment)> {
+        vec![(r, Alignment::default())]
     }
-    info!("Totally {} kinds of pairs and {} kinds of sequences were founded.", freq.len(), key_set.len());
-    kv_vec.sort_by(|a, b| b.2.cmp(a.2));
-    info!("Write pair counts to tsv file: {}", cnt_path);
-    for (k0, k1, v) in kv_vec {
-        let _ = writeln!(cnt_file, "{}\t{}\t{}", k0, k1, v);
+
+    fn ok() -> ExtractRes {
+        ExtractRes::Ok(flank(b"AAACCCGGGTTTA"), flank(b"CCCAAATTTGGGC"))
     }
 
-    info!("Write sequences to fastq file: {}", fq_out_path);
-    let mut key_vec = key_set.into_iter().collect::<Vec<u64>>();
-    key_vec.sort();
-    for k in key_vec {
-        let seq = recover_seq(k, flanking);
-        let id = format!("{}", k);
-        let qual = vec![b'~'; seq.len()];
-        let _ = fq_out.write(
-            &id,
-            Option::None,
-            seq.as_bytes(),
-            &qual,
-        );
+    #[test]
+    fn test_combine_mates_r1_found() {
+        let (r, rescued) = combine_mates(res(ok()), res(ExtractRes::ScoreTooLow), &params());
+        assert!(r[0].0 == ok() && !rescued);
+        let concatemer = ExtractRes::Concatemer(vec![]);
+        let (r, rescued) = combine_mates(res(ExtractRes::Concatemer(vec![])), res(ok()), &params());
+        assert!(r[0].0 == concatemer && !rescued);
+    }
+
+    #[test]
+    fn test_combine_mates_rescue_by_r2() {
+        // R1 doesn't reach the linker, or ends inside the right flank
+        for r1 in [ExtractRes::ScoreTooLow, ExtractRes::RightTooShort(flank(b"AAACCCGGGTTTA"))] {
+            let (r, rescued) = combine_mates(res(r1), res(ok()), &params());
+            assert!(r[0].0 == ok() && rescued);
+        }
+        let (r, rescued) = combine_mates(res(ExtractRes::ScoreTooLow), res(ExtractRes::Concatemer(vec![])), &params());
+        assert!(r[0].0 == ExtractRes::Concatemer(vec![]) && !rescued);
+    }
+
+    #[test]
+    fn test_combine_mates_no_rescue_after_left_too_short() {
+        // R2 reads through the fragment end into adapter
+        let (r, rescued) = combine_mates(res(ExtractRes::LeftTooShort), res(ok()), &params());
+        assert!(r[0].0 == ExtractRes::LeftTooShort && !rescued);
+        let (r, rescued) = combine_mates(res(ExtractRes::LeftTooShort), res(ExtractRes::LeftTooShort), &params());
+        assert!(r[0].0 == ExtractRes::LeftTooShort && !rescued);
+    }
+
+    #[test]
+    fn test_combine_mates_both_right_too_short() {
+        let r1 = ExtractRes::RightTooShort(flank(b"AAACCCGGGTTTA"));
+        let r2 = ExtractRes::RightTooShort(flank(b"GCCCAAATTTGGG"));
+        let (r, rescued) = combine_mates(res(r1), res(r2), &params());
+        assert!(r[0].0 == ok() && rescued);
+        // the combined flanks are filtered
+        let r1 = ExtractRes::RightTooShort(flank(b"AAACCCGGGTTTA"));
+        let r2 = ExtractRes::RightTooShort(flank(b"GCCCAANTTTGGG"));
+        let (r, rescued) = combine_mates(res(r1), res(r2), &params());
+        assert!(r[0].0 == ExtractRes::TooManyN && rescued);
+    }
+
+    #[test]
+    fn test_combine_mates_failure_reason() {
+        // R2's reason is reported only if linker is not found in R1
+        let (r, _) = combine_mates(res(ExtractRes::ScoreTooLow), res(ExtractRes::LeftTooShort), &params());
+        assert!(r[0].0 == ExtractRes::LeftTooShort);
+        let (r, _) = combine_mates(res(ExtractRes::LeftTooShort), res(ExtractRes::ScoreTooLow), &params());
+        assert!(r[0].0 == ExtractRes::LeftTooShort);
+        let (r, _) = combine_mates(res(ExtractRes::TrimmedTooShort), res(ExtractRes::ScoreTooLow), &params());
+        assert!(r[0].0 == ExtractRes::TrimmedTooShort);
+    }
+
+    const LINKER: &[u8] = b"TAGCGTGCGGGTGCCAGGGC";
+
+    fn linkers() -> Linkers {
+        let structure = ReadStructure::parse("13F E L E 13F", b"GTTGGA", Some(LINKER)).unwrap();
+        Linkers { layouts: structure.layouts, boundary: None, prefilter: None }
+    }
+
+    fn read(parts: &[&[u8]]) -> (Vec<u8>, Vec<u8>) {
+        let seq = parts.concat();
+        let qual = vec![b'I'; seq.len()];
+        (seq, qual)
     }
 
-    info!("{}", counter);
+    #[test]
+    fn test_search_read_reports_found_orientation() {
+        // linker found at the read start by the first pattern, the search stops there
+        // and reports why it failed, not the score of the other orientation
+        let (seq, qual) = read(&[b"ACGTA", b"GTTGGA", LINKER, b"TCCAAC", b"CCCAAATTTGGGCATCA"]);
+        let res = search_read(&seq, &qual, &linkers(), &params());
+        assert_eq!(res.len(), 1);
+        assert!(res[0].0 == ExtractRes::LeftTooShort);
+        // the reverse complement read is found by the second pattern
+        let (seq, qual) = (revcomp(&seq), qual);
+        let res = search_read(&seq, &qual, &linkers(), &params());
+        assert_eq!(res.len(), 2);
+        assert!(res[0].0 == ExtractRes::ScoreTooLow);
+        assert!(res[1].0 == ExtractRes::RightTooShort(flank(b"GCCCAAATTTGGG")));
+    }
+
+    #[test]
+    fn test_process_trimmed_too_short() {
+        let trimmer = Trimmer::new(ADAPTER_R1.as_bytes(), ADAPTER_R2.as_bytes(), 40);
+        let mut counter = TrimCounter::new();
+        let (seq, qual) = read(&[b"ACGTAACCGGTTACGTAACC", ADAPTER_R1.as_bytes()]);
+        let rec = fastq::Record::with_attrs("r1", None, &seq, &qual);
+        let (res, rescued, _) = process_pair(&rec, None, &linkers(), &params(), Some(&trimmer), &mut counter, None);
+        assert!(res[0].0 == ExtractRes::TrimmedTooShort && !rescued);
+        // R2 without linker keeps the reason of R1
+        let rec2 = fastq::Record::with_attrs("r1", None, &seq, &qual);
+        let (res, _, _) = process_pair(&rec, Some(&rec2), &linkers(), &params(), Some(&trimmer), &mut counter, None);
+        assert!(res[0].0 == ExtractRes::TrimmedTooShort);
+    }
+
+    fn unit() -> Vec<u8> {
+        [b"GTTGGA", LINKER, b"TCCAAC"].concat()
+    }
+
+    #[test]
+    fn test_concatemer_pairs() {
+        // the second linker is on the reverse strand
+        let (seq, qual) = read(&[b"ACGTAACCGGTTA", &unit(), b"CCCAAATTTGGGC", b"GATCCATGCATGA", &revcomp(unit()), b"TTTGGGAAACCCA"]);
+        let res = search_read(&seq, &qual, &linkers(), &params());
+        let pairs = match &res[res.len()-1].0 {
+            ExtractRes::Concatemer(pairs) => pairs,
+            _ => panic!("not reported as concatemer"),
+        };
+        assert!(pairs == &vec![
+            (flank(b"ACGTAACCGGTTA"), flank(b"CCCAAATTTGGGC"), false),
+            (flank(b"GATCCATGCATGA"), flank(b"TTTGGGAAACCCA"), true),
+        ]);
+    }
+
+    #[test]
+    fn test_concatemer_flanks_not_overlap() {
+        // 10 bases between the linkers, can't be the flank of both
+        let (seq, qual) = read(&[b"ACGTAACCGGTTA", &unit(), b"CCCAAATTTG", &unit(), b"TTTGGGAAACCCA"]);
+        let res = search_read(&seq, &qual, &linkers(), &params());
+        assert!(res[res.len()-1].0 == ExtractRes::Concatemer(vec![]));
+    }
+
+    #[test]
+    fn test_single_linker_not_concatemer() {
+        let (seq, qual) = read(&[b"TTGA", b"ACGTAACCGGTTA", &unit(), b"CCCAAATTTGGGC", b"ACGTTGCATCAGGA"]);
+        let res = search_read(&seq, &qual, &linkers(), &params());
+        assert_eq!(res.len(), 1);
+        assert!(res[0].0 == ExtractRes::Ok(flank(b"ACGTAACCGGTTA"), flank(b"CCCAAATTTGGGC")));
+    }
+
+    fn qual_params(min_base_qual: u8, min_mean_qual: f32, max_expected_errors: f32) -> ExtractParams {
+        ExtractParams { min_base_qual, min_mean_qual, max_expected_errors, ..params() }
+    }
+
+    /// Flank of 13 bases with phred qualities, Q40 except the given ones.
+    fn qual_flank(low: &[(usize, u8)]) -> Flank {
+        let mut qual = vec![QUAL_OFFSET + 40; 13];
+        for (i, q) in low { qual[*i] = QUAL_OFFSET + q }
+        Flank::new(b"ACGTAACCGGTTA", &qual)
+    }
+
+    #[test]
+    fn test_min_base_qual() {
+        let p = qual_params(20, 0.0, f32::INFINITY);
+        assert!(!qual_flank(&[(3, 20)]).is_low_quality(&p));
+        assert!(qual_flank(&[(3, 19)]).is_low_quality(&p));
+        assert!(!qual_flank(&[]).is_low_quality(&p));
+        // no quality, like the flanks of fasta input
+        assert!(!Flank::new(b"ACGT", b"").is_low_quality(&p));
+    }
+
+    #[test]
+    fn test_mean_vs_min_qual() {
+        // a single low base passes the mean, fails the min
+        let f = qual_flank(&[(0, 10)]);
+        assert!(!f.is_low_quality(&qual_params(0, 30.0, f32::INFINITY)));
+        assert!(f.is_low_quality(&qual_params(20, 0.0, f32::INFINITY)));
+        // all bases of Q25 pass the min, fail the mean
+        let f = Flank::new(b"ACGTAACCGGTTA", &[QUAL_OFFSET + 25; 13]);
+        assert!(!f.is_low_quality(&qual_params(20, 0.0, f32::INFINITY)));
+        assert!(f.is_low_quality(&qual_params(0, 30.0, f32::INFINITY)));
+    }
+
+    #[test]
+    fn test_expected_errors() {
+        // a Q10 base is 0.1 expected error
+        let f = qual_flank(&[(0, 10)]);
+        assert!(!f.is_low_quality(&qual_params(0, 0.0, 0.2)));
+        assert!(f.is_low_quality(&qual_params(0, 0.0, 0.05)));
+    }
+
+    #[test]
+    fn test_edit_count() {
+        let p = params();
+        let with = |i: usize, b: &[u8]| [&LINKER[..i], b, &LINKER[i..]].concat();
+        let read = [b"AAAA".as_ref(), LINKER, b"TTTT"].concat();
+        assert_eq!(edit_count(&align_linker(&read, LINKER, 0, read.len(), &p), LINKER), 0);
+        // a base deleted from the read
+        let read = [b"AAAA".as_ref(), &LINKER[..10], &LINKER[11..], b"TTTT"].concat();
+        assert_eq!(edit_count(&align_linker(&read, LINKER, 0, read.len(), &p), LINKER), 1);
+        // a base inserted into the read
+        let read = [b"AAAA".as_ref(), &with(10, b"A"), b"TTTT"].concat();
+        assert_eq!(edit_count(&align_linker(&read, LINKER, 0, read.len(), &p), LINKER), 1);
+        // substitution and deletion
+        let mut linker = LINKER.to_vec();
+        linker[3] = b'A';
+        linker.remove(12);
+        let read = [b"AAAA".as_ref(), &linker, b"TTTT"].concat();
+        assert_eq!(edit_count(&align_linker(&read, LINKER, 0, read.len(), &p), LINKER), 2);
+    }
+
+    #[test]
+    fn test_edit_count_wildcard() {
+        let p = params();
+        let pattern = [&LINKER[..8], b"NNNN".as_ref(), &LINKER[12..]].concat();
+        let read = [b"AAAA".as_ref(), LINKER, b"TTTT"].concat();
+        let aln = align_linker(&read, &pattern, 0, read.len(), &p);
+        assert_eq!(edit_count(&aln, &pattern), 0);
+        let mut linker = LINKER.to_vec();
+        linker[2] = b'A';
+        let read = [b"AAAA".as_ref(), &linker, b"TTTT"].concat();
+        let aln = align_linker(&read, &pattern, 0, read.len(), &p);
+        assert_eq!(edit_count(&aln, &pattern), 1);
+        assert!(ExtractParams { max_edit_dist: Some(1), ..params() }.is_linker(&aln, &pattern));
+        assert!(!ExtractParams { max_edit_dist: Some(0), ..params() }.is_linker(&aln, &pattern));
+    }
 }
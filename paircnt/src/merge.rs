@@ -0,0 +1,242 @@
+use std::fmt;
+use std::cmp;
+
+use bio::alphabets::dna::revcomp;
+
+
+const QUAL_OFFSET: u8 = 33;
+const MAX_QUAL: u8 = 41;
+const MIN_QUAL: u8 = 2;
+const OVERLAP_BIN: usize = 10;
+
+
+pub struct Merged {
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+    pub overlap: usize,
+}
+
+
+/// Find the best overlap between R1 and reverse complement of R2.
+/// `offset` is the position of R2's (reverse complemented) first base
+/// relative to R1, negative offset means the insert is shorter than reads,
+/// and the read-through adapter on both reads will be trimmed.
+/// Return (offset, overlap length) of the overlap with fewest mismatches.
+fn find_overlap(seq1: &[u8], seq2_rc: &[u8], min_overlap: usize, max_mismatch_ratio: f32) -> Option<(isize, usize)> {
+    let len1 = seq1.len() as isize;
+    let len2 = seq2_rc.len() as isize;
+    let min_overlap = min_overlap as isize;
+    let mut best: Option<(isize, usize, usize)> = None;  // offset, overlap, mismatches
+    for offset in (min_overlap - len2)..=(len1 - min_overlap) {
+        let start = cmp::max(0, offset);
+        let end = cmp::min(len1, offset + len2);
+        let overlap = (end - start) as usize;
+        let max_mismatch = (overlap as f32 * max_mismatch_ratio) as usize;
+        let mut mismatch = 0;
+        for p in start..end {
+            if seq1[p as usize] != seq2_rc[(p - offset) as usize] {
+                mismatch += 1;
+                if mismatch > max_mismatch { break }
+            }
+        }
+        if mismatch > max_mismatch { continue }
+        let better = match best {
+            None => true,
+            // compare mismatch ratio, prefer longer overlap when tie
+            Some((_, b_ovl, b_mis)) => {
+                let (a, b) = (mismatch * b_ovl, b_mis * overlap);
+                a < b || (a == b && overlap > b_ovl)
+            },
+        };
+        if better { best = Some((offset, overlap, mismatch)) }
+    }
+    best.map(|(offset, overlap, _)| (offset, overlap))
+}
+
+
+/// Merge overlapped mates to one consensus read.
+/// Within the overlap region, the base with higher quality is called,
+/// quality is summed when two bases agree and subtracted when disagree.
+pub fn merge_mates(
+        seq1: &[u8], qual1: &[u8],
+        seq2: &[u8], qual2: &[u8],
+        min_overlap: usize, max_mismatch_ratio: f32) -> Option<Merged> {
+    let seq2_rc = revcomp(seq2);
+    let qual2_r: Vec<u8> = qual2.iter().rev().cloned().collect();
+    let (offset, overlap) = find_overlap(seq1, &seq2_rc, min_overlap, max_mismatch_ratio)?;
+
+    let len1 = seq1.len() as isize;
+    let len2 = seq2_rc.len() as isize;
+    let end = if offset >= 0 {
+        cmp::max(len1, offset + len2)
+    } else {
+        cmp::min(len1, offset + len2)
+    };
+    let mut seq = Vec::with_capacity(end as usize);
+    let mut qual = Vec::with_capacity(end as usize);
+    for p in 0..end {
+        let in1 = p < len1;
+        let in2 = p - offset >= 0 && p - offset < len2;
+        let (b, q) = match (in1, in2) {
+            (true, false) => (seq1[p as usize], qual1[p as usize]),
+            (false, true) => (seq2_rc[(p - offset) as usize], qual2_r[(p - offset) as usize]),
+            _ => {
+                let (b1, q1) = (seq1[p as usize], qual1[p as usize].saturating_sub(QUAL_OFFSET));
+                let i2 = (p - offset) as usize;
+                let (b2, q2) = (seq2_rc[i2], qual2_r[i2].saturating_sub(QUAL_OFFSET));
+                if b1 == b2 {
+                    (b1, cmp::min(q1 + q2, MAX_QUAL) + QUAL_OFFSET)
+                } else if q1 >= q2 {
+                    (b1, cmp::max(q1 - q2, MIN_QUAL) + QUAL_OFFSET)
+                } else {
+                    (b2, cmp::max(q2 - q1, MIN_QUAL) + QUAL_OFFSET)
+                }
+            },
+        };
+        seq.push(b);
+        qual.push(q);
+    }
+    Some(Merged { seq, qual, overlap })
+}
+
+
+pub struct MergeCounter {
+    merged: u64,
+    unmerged: u64,
+    // number of merged pairs in each overlap length bin
+    overlap_hist: Vec<u64>,
+}
+
+impl MergeCounter {
+    pub fn new() -> Self {
+        Self {
+            merged: 0,
+            unmerged: 0,
+            overlap_hist: vec![],
+        }
+    }
+
+    pub fn count(&mut self, overlap: Option<usize>) {
+        match overlap {
+            Some(ovl) => {
+                self.merged += 1;
+                let bin = ovl / OVERLAP_BIN;
+                if bin >= self.overlap_hist.len() {
+                    self.overlap_hist.resize(bin + 1, 0);
+                }
+                self.overlap_hist[bin] += 1;
+            },
+            None => { self.unmerged += 1 },
+        }
+    }
+
+    pub fn merge(&mut self, other: &MergeCounter) {
+        self.merged += other.merged;
+        self.unmerged += other.unmerged;
+        if self.overlap_hist.len() < other.overlap_hist.len() {
+            self.overlap_hist.resize(other.overlap_hist.len(), 0);
+        }
+        for (h, o) in self.overlap_hist.iter_mut().zip(&other.overlap_hist) {
+            *h += o;
+        }
+    }
+}
+
+impl fmt::Display for MergeCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.merged + self.unmerged;
+        let ratio = |c, total| {
+            if total == 0 { return "0%".to_string(); }
+            format!("{:.2}%", ((c*100) as f64) / (total as f64))
+        };
+        write!(f,
+            "Merge result:
+    merged pairs\t{}\t{}
+    unmerged pairs\t{}\t{}
+total pairs: {}
+overlap length distribution:\n",
+            self.merged, ratio(self.merged, total),
+            self.unmerged, ratio(self.unmerged, total),
+            total,
+        )?;
+        for (bin, c) in self.overlap_hist.iter().enumerate() {
+            if *c == 0 { continue }
+            writeln!(f, "    {}-{}\t{}\t{}",
+                bin * OVERLAP_BIN, (bin + 1) * OVERLAP_BIN - 1,
+                c, ratio(*c, self.merged))?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pseudo random sequence of length n (xorshift)
+    fn random_seq(n: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..n).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state % 4) as usize]
+        }).collect()
+    }
+
+    fn qual(n: usize, q: u8) -> Vec<u8> {
+        vec![q + QUAL_OFFSET; n]
+    }
+
+    #[test]
+    fn test_merge_overlap() {
+        let insert = random_seq(60, 1);
+        let seq1 = insert[..40].to_vec();
+        let seq2 = revcomp(&insert[20..]);
+        let merged = merge_mates(&seq1, &qual(40, 20), &seq2, &qual(40, 30), 10, 0.1).unwrap();
+        assert_eq!(merged.seq, insert);
+        assert_eq!(merged.overlap, 20);
+        assert_eq!(merged.qual[..20], qual(20, 20)[..]);
+        // qualities summed in the overlap, capped
+        assert_eq!(merged.qual[20..40], qual(20, MAX_QUAL)[..]);
+        assert_eq!(merged.qual[40..], qual(20, 30)[..]);
+    }
+
+    #[test]
+    fn test_merge_mismatch() {
+        let insert = random_seq(60, 2);
+        let mut seq1 = insert[..40].to_vec();
+        seq1[30] = if seq1[30] == b'A' { b'C' } else { b'A' };
+        let seq2 = revcomp(&insert[20..]);
+        let mut qual2 = qual(40, 30);
+        // base 30 of insert is base 29 of R2
+        qual2[29] = 10 + QUAL_OFFSET;
+        let merged = merge_mates(&seq1, &qual(40, 25), &seq2, &qual2, 10, 0.1).unwrap();
+        // the base with higher quality is called
+        assert_eq!(merged.seq[30], seq1[30]);
+        assert_eq!(merged.qual[30], 15 + QUAL_OFFSET);
+        assert_eq!(merged.seq[..30], insert[..30]);
+        assert_eq!(merged.seq[31..], insert[31..]);
+    }
+
+    #[test]
+    fn test_merge_read_through() {
+        // insert shorter than reads, the adapters are trimmed
+        let insert = random_seq(30, 3);
+        let mut seq1 = insert.clone();
+        seq1.extend(random_seq(10, 4));
+        let mut seq2 = revcomp(&insert);
+        seq2.extend(random_seq(10, 5));
+        let merged = merge_mates(&seq1, &qual(40, 30), &seq2, &qual(40, 30), 10, 0.1).unwrap();
+        assert_eq!(merged.seq, insert);
+        assert_eq!(merged.overlap, 30);
+    }
+
+    #[test]
+    fn test_no_overlap() {
+        let seq1 = random_seq(40, 6);
+        let seq2 = random_seq(40, 7);
+        assert!(merge_mates(&seq1, &qual(40, 30), &seq2, &qual(40, 30), 20, 0.1).is_none());
+    }
+}
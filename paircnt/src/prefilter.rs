@@ -0,0 +1,165 @@
+use bio::pattern_matching::myers::MyersBuilder;
+use bio::pattern_matching::myers::long::Myers;
+
+use crate::structure::WILDCARD;
+
+
+/// Locate the candidate linker positions in read by Myers bit-parallel edit distance
+/// search, so the full semiglobal alignment only runs on a small window of read.
+///
+/// Every edit costs at least some score, so the alignments pass the score threshold
+/// are all within `max_dist` edits, and end at the positions reported by the search.
+/// If `use_window` is false, the prefilter only rejects the reads without any hit.
+pub struct Prefilter {
+    matchers: Vec<Myers<u64>>,
+    max_dist: Vec<usize>,
+    pattern_len: Vec<usize>,
+    use_window: bool,
+}
+
+
+/// Where to run the full aligner.
+pub enum Window {
+    // no position within the max edit distance, alignment score must be too low
+    NoHit,
+    // candidate ends are close, align in seq[start..end]
+    Region(usize, usize),
+    // candidates are far apart (like multiple linkers), align the whole read
+    Ambiguous,
+}
+
+
+impl Prefilter {
+    pub fn new(patterns: &[Vec<u8>], max_dist: Vec<usize>, use_window: bool) -> Self {
+        // wildcard bases of pattern match any base, as in the aligner
+        let mut builder = MyersBuilder::new();
+        builder.ambig(WILDCARD, 0..=255u8);
+        let matchers = patterns.iter().map(|p| builder.build_long_64(p.iter())).collect();
+        let pattern_len = patterns.iter().map(|p| p.len()).collect();
+        Self { matchers, max_dist, pattern_len, use_window }
+    }
+
+    pub fn window(&self, pattern_idx: usize, seq: &[u8]) -> Window {
+        let max_dist = self.max_dist[pattern_idx];
+        let len = self.pattern_len[pattern_idx];
+        let mut first_end: Option<usize> = None;
+        let mut last_end = 0;
+        for (end, _) in self.matchers[pattern_idx].find_all_end(seq.iter(), max_dist) {
+            if first_end.is_none() { first_end = Some(end) }
+            last_end = end;
+        }
+        let first_end = match first_end {
+            Some(e) => e,
+            None => return Window::NoHit,
+        };
+        if !self.use_window || last_end - first_end > len {
+            return Window::Ambiguous
+        }
+        // alignment ends at `end` starts at least `end + 1 - len - max_dist`
+        let start = (first_end + 1).saturating_sub(len + max_dist);
+        Window::Region(start, last_end + 1)
+    }
+}
+
+
+/// Agreement of prefilter and full aligner on the subsampled reads.
+pub struct PrefilterCheck {
+    pub checked: u64,
+    pub discordant: u64,
+}
+
+impl PrefilterCheck {
+    pub fn new() -> Self {
+        Self { checked: 0, discordant: 0 }
+    }
+
+    pub fn merge(&mut self, other: &PrefilterCheck) {
+        self.checked += other.checked;
+        self.discordant += other.discordant;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ExtractParams, ExtractRes, Linkers, search_read, same_result};
+    use crate::structure::ReadStructure;
+
+    const LINKER: &[u8] = b"TAGCGTGCGGGTGCCAGGGC";
+    const LEFT: &[u8] = b"ACGTAACCGGTTA";
+    const RIGHT: &[u8] = b"CCCAAATTTGGGC";
+
+    fn params() -> ExtractParams {
+        ExtractParams {
+            score_ratio_thresh: 0.6, max_edit_dist: None,
+            match_score: 1, mismatch_score: -1, gap_open: -1, gap_extend: -1,
+            max_n: 0, min_base_qual: 0, min_mean_qual: 0.0, max_expected_errors: f32::INFINITY,
+        }
+    }
+
+    /// Linkers searched with and without prefilter.
+    fn linkers() -> (Linkers, Linkers) {
+        let structure = ReadStructure::parse("13F E L E 13F", b"GTTGGA", Some(LINKER)).unwrap();
+        let patterns = structure.patterns();
+        let max_dist = patterns.iter().map(|p| params().max_edits(p.len()).unwrap()).collect();
+        let prefilter = Prefilter::new(&patterns, max_dist, true);
+        let filtered = Linkers { layouts: structure.layouts.clone(), boundary: None, prefilter: Some(prefilter) };
+        let full = Linkers { layouts: structure.layouts, boundary: None, prefilter: None };
+        (filtered, full)
+    }
+
+    fn check(seq: &[u8]) -> (Window, ExtractRes) {
+        let (filtered, full) = linkers();
+        let qual = vec![b'I'; seq.len()];
+        let res = search_read(seq, &qual, &filtered, &params());
+        let full_res = search_read(seq, &qual, &full, &params());
+        assert!(same_result(&res, &full_res));
+        let window = filtered.prefilter.as_ref().unwrap().window(0, seq);
+        (window, res.into_iter().last().unwrap().0)
+    }
+
+    #[test]
+    fn test_prefilter_hit() {
+        // a mismatch and a deletion in linker
+        let seq = [b"TTGA", LEFT, b"GTTGGA", b"TAGCGTGCGGTGCCTGGGC", b"TCCAAC", RIGHT, b"ACGTTG"].concat();
+        let (window, res) = check(&seq);
+        assert!(matches!(window, Window::Region(s, e) if s <= 17 && e >= 48));
+        assert!(res == ExtractRes::Ok(
+            crate::Flank::new(LEFT, &[b'I'; 13]), crate::Flank::new(RIGHT, &[b'I'; 13])));
+    }
+
+    #[test]
+    fn test_prefilter_no_hit() {
+        let seq = b"ACACACACACTTTTTTTTTTGGGGCCCCATATATATATCGCGCGCGAATTAATT".to_vec();
+        let (window, res) = check(&seq);
+        assert!(matches!(window, Window::NoHit));
+        assert!(res == ExtractRes::ScoreTooLow);
+    }
+
+    #[test]
+    fn test_prefilter_linker_at_read_end() {
+        // the last bases of the enzyme site are out of read
+        let seq = [b"TTGA", LEFT, b"GTTGGA", LINKER, b"TCCA"].concat();
+        let (window, res) = check(&seq);
+        assert!(matches!(window, Window::Region(_, e) if e == seq.len()));
+        assert!(res == ExtractRes::RightTooShort(crate::Flank::new(LEFT, &[b'I'; 13])));
+    }
+
+    #[test]
+    fn test_prefilter_multiple_linkers() {
+        let unit = [b"GTTGGA", LINKER, b"TCCAAC"].concat();
+        let seq = [LEFT, &unit, RIGHT, LEFT, &unit, RIGHT].concat();
+        let (window, res) = check(&seq);
+        assert!(matches!(window, Window::Ambiguous));
+        assert!(matches!(res, ExtractRes::Concatemer(_)));
+    }
+
+    #[test]
+    fn test_prefilter_check_merge() {
+        let mut check = PrefilterCheck::new();
+        check.merge(&PrefilterCheck { checked: 10, discordant: 1 });
+        check.merge(&PrefilterCheck { checked: 5, discordant: 0 });
+        assert_eq!((check.checked, check.discordant), (15, 1));
+    }
+}
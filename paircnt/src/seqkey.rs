@@ -0,0 +1,223 @@
+use std::fmt;
+
+use bio::alphabets::dna::revcomp;
+
+
+const SEQ_NT4_TABLE: [u64; 256] = [
+    0, 1, 2, 3,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 0, 4, 1,  4, 4, 4, 2,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  3, 3, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 0, 4, 1,  4, 4, 4, 2,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  3, 3, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,
+    4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4,  4, 4, 4, 4
+];
+
+const IDX_TABLE: [u8; 4] = [
+    b'A', b'C', b'G', b'T'
+];
+
+// number of bases can be packed in one u64 word
+const WORD_BASES: usize = 32;
+
+
+/// Compressed (2 bits per base) form of a flanking sequence.
+/// Sequences not longer than 32 bp are packed in u64, not longer than 64 bp
+/// in u128, the longer sequences are packed in a vector of u64 words.
+/// Sequences contain ambiguous bases can't be packed, are stored as it is.
+/// Variable-length flanks are packed with their lengths.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SeqKey {
+    Short(u64),
+    Mid(u128),
+    Long(Vec<u64>),
+    Ambiguous(Vec<u8>),
+    Var(usize, Vec<u64>),
+}
+
+impl fmt::Display for SeqKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeqKey::Short(code) => write!(f, "{}", code),
+            SeqKey::Mid(code) => write!(f, "{}", code),
+            SeqKey::Long(words) => {
+                let hex: Vec<String> = words.iter().map(|w| format!("{:016x}", w)).collect();
+                write!(f, "{}", hex.join("-"))
+            },
+            SeqKey::Ambiguous(seq) => write!(f, "{}", String::from_utf8_lossy(seq)),
+            SeqKey::Var(_, _) => write!(f, "{}", recover_seq(self, 0)),
+        }
+    }
+}
+
+
+fn compress_u128(seq: &[u8], canonical: bool) -> (u128, bool) {
+    let mut res: u128 = 0;
+    let mut res_rc: u128 = 0;
+    let end = seq.len() - 1;
+    for i in 0..seq.len() {
+        let m = SEQ_NT4_TABLE[seq[i] as usize] as u128;
+        res |= m << (i*2);
+        res_rc |= (3 - m) << ((end - i)*2);
+    }
+    if canonical && res > res_rc { (res_rc, true) } else { (res, false) }
+}
+
+
+fn pack_words(seq: &[u8]) -> Vec<u64> {
+    seq.chunks(WORD_BASES).map(|chunk| {
+        let mut word: u64 = 0;
+        for (i, b) in chunk.iter().enumerate() {
+            word |= SEQ_NT4_TABLE[*b as usize] << (i*2);
+        }
+        word
+    }).collect()
+}
+
+
+/// Number of ambiguous (not A, C, G or T) bases in sequence.
+pub fn n_count(seq: &[u8]) -> usize {
+    seq.iter().filter(|b| SEQ_NT4_TABLE[**b as usize] > 3).count()
+}
+
+
+/// Compress sequence to key, if `canonical` the sequence and its
+/// reverse complement share the same key.
+/// Return the key and whether it stores the reverse complement.
+pub fn compress_seq(seq: &[u8], canonical: bool) -> (SeqKey, bool) {
+    if n_count(seq) > 0 {
+        let seq: Vec<u8> = seq.iter().map(|b| {
+            let m = SEQ_NT4_TABLE[*b as usize];
+            if m > 3 { b'N' } else { IDX_TABLE[m as usize] }
+        }).collect();
+        let seq_rc = revcomp(&seq);
+        if canonical && seq > seq_rc { (SeqKey::Ambiguous(seq_rc), true) } else { (SeqKey::Ambiguous(seq), false) }
+    } else if seq.len() <= WORD_BASES {
+        let (code, flipped) = compress_u128(seq, canonical);
+        (SeqKey::Short(code as u64), flipped)
+    } else if seq.len() <= WORD_BASES * 2 {
+        let (code, flipped) = compress_u128(seq, canonical);
+        (SeqKey::Mid(code), flipped)
+    } else {
+        let words = pack_words(seq);
+        let words_rc = pack_words(&revcomp(seq));
+        if canonical && words > words_rc { (SeqKey::Long(words_rc), true) } else { (SeqKey::Long(words), false) }
+    }
+}
+
+
+/// Compress variable-length sequence to key, the key keeps the sequence length.
+pub fn compress_var(seq: &[u8], canonical: bool) -> (SeqKey, bool) {
+    if n_count(seq) > 0 {
+        return compress_seq(seq, canonical)
+    }
+    let words = pack_words(seq);
+    let words_rc = pack_words(&revcomp(seq));
+    if canonical && words > words_rc {
+        (SeqKey::Var(seq.len(), words_rc), true)
+    } else {
+        (SeqKey::Var(seq.len(), words), false)
+    }
+}
+
+
+/// Recover sequence of length `k` from key, variable-length keys use their own lengths.
+pub fn recover_seq(key: &SeqKey, k: usize) -> String {
+    let k = match key {
+        SeqKey::Ambiguous(seq) => return String::from_utf8(seq.clone()).unwrap(),
+        SeqKey::Var(len, _) => *len,
+        _ => k,
+    };
+    let mut chars: Vec<u8> = Vec::with_capacity(k);
+    for i in 0..k {
+        let idx = match key {
+            SeqKey::Short(code) => (code >> (i*2)) & 3,
+            SeqKey::Mid(code) => ((code >> (i*2)) & 3) as u64,
+            SeqKey::Long(words) | SeqKey::Var(_, words) => (words[i / WORD_BASES] >> ((i % WORD_BASES)*2)) & 3,
+            SeqKey::Ambiguous(_) => unreachable!(),
+        };
+        chars.push(IDX_TABLE[idx as usize]);
+    }
+    String::from_utf8(chars).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // deterministic sequence of length n
+    fn seq(n: usize) -> Vec<u8> {
+        (0..n).map(|i| IDX_TABLE[(i * 7 + i / 3) % 4]).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for n in &[1, 13, 32, 33, 64, 65, 100] {
+            let s = seq(*n);
+            let (key, flipped) = compress_seq(&s, false);
+            assert!(!flipped);
+            match (n, &key) {
+                (n, SeqKey::Short(_)) if *n <= 32 => {},
+                (n, SeqKey::Mid(_)) if *n > 32 && *n <= 64 => {},
+                (n, SeqKey::Long(_)) if *n > 64 => {},
+                _ => panic!("Unexpected key kind of length {}", n),
+            }
+            assert_eq!(recover_seq(&key, *n).into_bytes(), s);
+        }
+    }
+
+    #[test]
+    fn test_canonical() {
+        for n in &[13, 40, 70] {
+            let s = seq(*n);
+            let rc = revcomp(&s);
+            let (k1, f1) = compress_seq(&s, true);
+            let (k2, f2) = compress_seq(&rc, true);
+            assert_eq!(k1, k2);
+            assert_ne!(f1, f2);
+            // the key stores the flipped sequence
+            let stored = if f1 { &rc } else { &s };
+            assert_eq!(&recover_seq(&k1, *n).into_bytes(), stored);
+            assert_ne!(compress_seq(&s, false).0, compress_seq(&rc, false).0);
+        }
+    }
+
+    #[test]
+    fn test_var_keys() {
+        for n in &[0, 13, 32, 33, 70] {
+            let s = seq(*n);
+            let (key, flipped) = compress_var(&s, false);
+            assert!(!flipped);
+            // the length is kept, whatever the `k` given
+            assert_eq!(recover_seq(&key, 5).into_bytes(), s);
+            assert_eq!(format!("{}", key).into_bytes(), s);
+            let (k1, f1) = compress_var(&s, true);
+            let (k2, f2) = compress_var(&revcomp(&s), true);
+            assert_eq!(k1, k2);
+            assert!(*n == 0 || f1 != f2);
+        }
+        // sequences with trailing A differ in length only
+        assert_ne!(compress_var(b"ACG", false).0, compress_var(b"ACGA", false).0);
+        assert_ne!(compress_var(b"ACGT", false).0, compress_seq(b"ACGT", false).0);
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        let (key, _) = compress_seq(b"ACGTNACGT", false);
+        assert_eq!(key, SeqKey::Ambiguous(b"ACGTNACGT".to_vec()));
+        assert_eq!(recover_seq(&key, 9), "ACGTNACGT");
+        let (k1, _) = compress_seq(b"AANCC", true);
+        let (k2, _) = compress_seq(b"GGNTT", true);
+        assert_eq!(k1, k2);
+    }
+}
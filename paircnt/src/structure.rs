@@ -0,0 +1,254 @@
+use bio::alphabets::dna::revcomp;
+
+use crate::trim::adapter_pos;
+
+
+// leading bases of adapters searched as the boundary of right flanks, the rest of adapter
+// is often of low quality, or differs by the index of library
+const ADAPTER_SEED: usize = 10;
+
+
+// base of the skipped segments inside the linker core, matches any base
+pub const WILDCARD: u8 = b'N';
+
+
+/// Segments at one side of the linker core, listed from the core outward.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SideSeg {
+    Flank(usize),
+    // barcode, UMI or spacer bases, skipped
+    Skip(usize),
+}
+
+
+/// Search pattern and the extraction rule of one read orientation.
+#[derive(Clone)]
+pub struct Layout {
+    pub pattern: Vec<u8>,
+    pub left: Vec<SideSeg>,
+    pub right: Vec<SideSeg>,
+}
+
+impl Layout {
+    /// Offset (from the core) and length of the flank of a side.
+    pub fn flank(side: &[SideSeg]) -> (usize, usize) {
+        let mut offset = 0;
+        for seg in side {
+            match seg {
+                SideSeg::Flank(l) => return (offset, *l),
+                SideSeg::Skip(l) => offset += l,
+            }
+        }
+        unreachable!()
+    }
+}
+
+
+/// Read structure compiled from the specification like `13F 6E 54L 6E 13F`.
+///
+/// Each segment is `<length><kind>` or a literal sequence, the kinds are:
+///   F: flank, exactly one at each side of the linker core
+///   E: enzyme site, as revcomp(enzyme) after the first fixed segment
+///   L: linker
+///   B, U, N: barcode, UMI and spacer, skipped
+/// Lengths of E and L are optional, checked if given. The core to search spans
+/// from the first to the last fixed segment (E, L and literal sequences), the
+/// skipped segments inside it are searched as wildcards. The read from the other
+/// strand is searched by the reverse complement core with the mirrored layout.
+pub struct ReadStructure {
+    pub layouts: Vec<Layout>,
+    // lengths of the left and right flanks
+    pub flank_lens: (usize, usize),
+    // the structure has a linker segment, `--linker` is ignored otherwise
+    pub uses_linker: bool,
+}
+
+impl ReadStructure {
+    /// The default `{flanking}F E L E {flanking}F` structure.
+    pub fn default_spec(flanking: usize) -> String {
+        format!("{}F E L E {}F", flanking, flanking)
+    }
+
+    pub fn parse(spec: &str, enzyme: &[u8], linker: Option<&[u8]>) -> Result<Self, String> {
+        // None for the fixed sequence
+        let mut segs: Vec<(Option<SideSeg>, Vec<u8>)> = vec![];
+        let mut linker_seen = false;
+        for token in spec.split_whitespace() {
+            let token = token.to_uppercase();
+            if token.bytes().all(|b| b"ACGTN".contains(&b)) {
+                segs.push((None, token.into_bytes()));
+                continue
+            }
+            let (kind_idx, _) = token.char_indices().last().unwrap();
+            let (len, kind) = token.split_at(kind_idx);
+            let len: Option<usize> = if len.is_empty() {
+                None
+            } else {
+                Some(len.parse().map_err(|_| format!("Invalid segment: {}", token))?)
+            };
+            let fixed = |seq: Vec<u8>| -> Result<(Option<SideSeg>, Vec<u8>), String> {
+                match len {
+                    Some(l) if l != seq.len() => Err(format!(
+                        "Length of segment {} not match the sequence {}", token, String::from_utf8_lossy(&seq))),
+                    _ => Ok((None, seq)),
+                }
+            };
+            let need_len = || len.ok_or(format!("Length is required for segment: {}", token));
+            match kind {
+                "F" => match need_len()? {
+                    0 => return Err(format!("Flank length should be positive: {}", token)),
+                    l => segs.push((Some(SideSeg::Flank(l)), vec![])),
+                },
+                "B" | "U" | "N" => segs.push((Some(SideSeg::Skip(need_len()?)), vec![])),
+                "E" => {
+                    let in_core = segs.iter().any(|(seg, _)| seg.is_none());
+                    let site = if in_core { revcomp(enzyme) } else { enzyme.to_vec() };
+                    segs.push(fixed(site)?);
+                },
+                "L" => {
+                    let linker = linker.ok_or("Linker sequence (--linker) is required by the read structure.")?;
+                    linker_seen = true;
+                    segs.push(fixed(linker.to_vec())?);
+                },
+                _ => return Err(format!("Unknown segment kind: {}", token)),
+            }
+        }
+
+        let fixed_idx: Vec<usize> = (0..segs.len()).filter(|i| segs[*i].0.is_none()).collect();
+        let (s, e) = match (fixed_idx.first(), fixed_idx.last()) {
+            (Some(s), Some(e)) => (*s, *e),
+            _ => return Err("No fixed segment to search in the read structure.".to_string()),
+        };
+        let mut core: Vec<u8> = vec![];
+        for (seg, seq) in &segs[s..=e] {
+            match seg {
+                None => core.extend_from_slice(seq),
+                Some(SideSeg::Skip(l)) => core.resize(core.len() + l, WILDCARD),
+                Some(SideSeg::Flank(_)) => return Err("Flanks should be outside the linker core.".to_string()),
+            }
+        }
+        let left: Vec<SideSeg> = segs[..s].iter().rev().map(|(seg, _)| seg.unwrap()).collect();
+        let right: Vec<SideSeg> = segs[e+1..].iter().map(|(seg, _)| seg.unwrap()).collect();
+        let n_flank = |side: &[SideSeg]| side.iter().filter(|s| matches!(s, SideSeg::Flank(_))).count();
+        if n_flank(&left) != 1 || n_flank(&right) != 1 {
+            return Err("Read structure needs exactly one flank at each side of the linker core.".to_string());
+        }
+        let flank_lens = (Layout::flank(&left).1, Layout::flank(&right).1);
+
+        let reverse = Layout { pattern: revcomp(&core), left: right.clone(), right: left.clone() };
+        let layouts = vec![Layout { pattern: core, left, right }, reverse];
+        Ok(Self { layouts, flank_lens, uses_linker: linker_seen })
+    }
+
+    pub fn patterns(&self) -> Vec<Vec<u8>> {
+        self.layouts.iter().map(|l| l.pattern.clone()).collect()
+    }
+}
+
+
+/// Boundaries of variable-length flanks, the flanks extend from the linker up to
+/// `max_len` bases, stop before the enzyme sites (both orientations) and the adapters.
+pub struct FlankBoundary {
+    pub max_len: usize,
+    sites: Vec<Vec<u8>>,
+    adapters: Vec<Vec<u8>>,
+}
+
+impl FlankBoundary {
+    pub fn new(max_len: usize, enzyme: &[u8], adapters: &[&[u8]]) -> Self {
+        let mut sites = vec![enzyme.to_vec()];
+        let enzyme_rc = revcomp(enzyme);
+        if enzyme_rc != enzyme { sites.push(enzyme_rc) }
+        let adapters = adapters.iter().map(|a| a[..a.len().min(ADAPTER_SEED)].to_vec()).collect();
+        Self { max_len, sites, adapters }
+    }
+
+    /// Length of the left flank ends at `end`.
+    pub fn left_len(&self, seq: &[u8], end: usize) -> usize {
+        let region = &seq[end.saturating_sub(self.max_len)..end];
+        let mut len = region.len();
+        for site in &self.sites {
+            if let Some(i) = region.windows(site.len()).rposition(|w| w == &site[..]) {
+                len = len.min(region.len() - i - site.len());
+            }
+        }
+        len
+    }
+
+    /// Length of the right flank starts at `start`, adapter is only at the 3' of read.
+    /// It's searched in the rest of read as in trimming, so the partial adapter at read end is found.
+    pub fn right_len(&self, seq: &[u8], start: usize) -> usize {
+        if start >= seq.len() { return 0 }
+        let region = &seq[start..seq.len().min(start + self.max_len)];
+        let mut len = region.len();
+        for site in &self.sites {
+            if let Some(i) = region.windows(site.len()).position(|w| w == &site[..]) {
+                len = len.min(i);
+            }
+        }
+        for adapter in &self.adapters {
+            if let Some(i) = adapter_pos(&seq[start..], adapter) { len = len.min(i) }
+        }
+        len
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_asymmetric_flanks() {
+        let s = ReadStructure::parse("12F 6E 54L 6E 15F", b"GTTGGA", Some(&[b'A'; 54])).unwrap();
+        assert_eq!(s.flank_lens, (12, 15));
+        assert_eq!(Layout::flank(&s.layouts[0].left), (0, 12));
+        assert_eq!(Layout::flank(&s.layouts[0].right), (0, 15));
+        // the reverse layout is mirrored
+        assert_eq!(Layout::flank(&s.layouts[1].left), (0, 15));
+        assert_eq!(Layout::flank(&s.layouts[1].right), (0, 12));
+    }
+
+    #[test]
+    fn test_parse_skip_in_core() {
+        let s = ReadStructure::parse("13F 6E 3U ACGT 2B 13F", b"GTTGGA", None).unwrap();
+        assert!(!s.uses_linker);
+        assert_eq!(s.layouts[0].pattern, b"GTTGGANNNACGT".to_vec());
+        assert_eq!(s.layouts[1].pattern, b"ACGTNNNTCCAAC".to_vec());
+        assert_eq!(Layout::flank(&s.layouts[0].right), (2, 13));
+        assert_eq!(Layout::flank(&s.layouts[1].left), (2, 13));
+    }
+
+    #[test]
+    fn test_parse_enzyme_without_linker() {
+        let s = ReadStructure::parse("13F E 3U ACGT E 13F", b"GTTGGA", None).unwrap();
+        assert_eq!(s.layouts[0].pattern, b"GTTGGANNNACGTTCCAAC".to_vec());
+        assert_eq!(s.layouts[1].pattern, b"GTTGGAACGTNNNTCCAAC".to_vec());
+        // the site starts the core is the enzyme, the ones after are reverse complement
+        let s = ReadStructure::parse("13F ACGT E 13F", b"GTTGGA", None).unwrap();
+        assert_eq!(s.layouts[0].pattern, b"ACGTTCCAAC".to_vec());
+    }
+
+    #[test]
+    fn test_flank_boundary() {
+        let b = FlankBoundary::new(20, b"GTTGGA", &[b"AGATCGGAAGAGCACACGTC"]);
+        // adapter with a mismatch
+        assert_eq!(b.right_len(b"CCCCTTTTTAGATCGGTAGAGCACACGTC", 2), 7);
+        // partial adapter at read end
+        assert_eq!(b.right_len(b"CCCCTTTTTCCCCCAGATC", 2), 12);
+        // enzyme site, and the max length
+        assert_eq!(b.right_len(b"CCCCTTTTTTCCAACAAAA", 2), 7);
+        assert_eq!(b.right_len(&[b'C'; 40], 2), 20);
+        assert_eq!(b.left_len(b"AAGTTGGACCCCTTTTT", 17), 9);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ReadStructure::parse("13F E L E 13F", b"GTTGGA", None).is_err());
+        assert!(ReadStructure::parse("13F E 5F E 13F", b"GTTGGA", None).is_err());
+        assert!(ReadStructure::parse("13F 5E 13F", b"GTTGGA", None).is_err());
+        assert!(ReadStructure::parse("0F E 13F", b"GTTGGA", None).is_err());
+        assert!(ReadStructure::parse("13F E L E 13µ", b"GTTGGA", Some(b"ACGT")).is_err());
+        assert!(ReadStructure::parse("13F E µ 13F", b"GTTGGA", None).is_err());
+    }
+}
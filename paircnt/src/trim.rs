@@ -0,0 +1,180 @@
+use std::fmt;
+
+
+// TruSeq adapters at 3' of R1 and R2
+pub const ADAPTER_R1: &str = "AGATCGGAAGAGCACACGTCTGAACTCCAGTCA";
+pub const ADAPTER_R2: &str = "AGATCGGAAGAGCGTCGTGTAGGGAAAGAGTGT";
+
+// minimal overlap of adapter at the read end to be trimmed
+const ADAPTER_MIN_OVERLAP: usize = 5;
+// max ratio of mismatches in the adapter overlap
+const ADAPTER_MAX_MISMATCH: f32 = 0.1;
+// minimal length of poly-G tail to be trimmed, one mismatch allowed in each 8 bases
+const POLY_G_MIN_LEN: usize = 10;
+const POLY_G_MISMATCH_STEP: usize = 8;
+
+
+/// Position of the adapter (or its prefix at read end) in sequence.
+pub fn adapter_pos(seq: &[u8], adapter: &[u8]) -> Option<usize> {
+    for i in 0..seq.len() {
+        let overlap = (seq.len() - i).min(adapter.len());
+        if overlap < ADAPTER_MIN_OVERLAP { break }
+        let n_mismatch = seq[i..i+overlap].iter().zip(adapter).filter(|(a, b)| a != b).count();
+        if n_mismatch as f32 <= overlap as f32 * ADAPTER_MAX_MISMATCH { return Some(i) }
+    }
+    None
+}
+
+
+/// Length of the poly-G tail, 0 if it's shorter than `POLY_G_MIN_LEN`.
+fn poly_g_len(seq: &[u8]) -> usize {
+    let mut n_mismatch = 0;
+    let mut len = 0;
+    for (i, b) in seq.iter().rev().enumerate() {
+        let n = i + 1;
+        if *b != b'G' {
+            n_mismatch += 1;
+            if n_mismatch > n / POLY_G_MISMATCH_STEP + 1 { break }
+        } else if n_mismatch <= n / POLY_G_MISMATCH_STEP {
+            len = n;
+        }
+    }
+    if len >= POLY_G_MIN_LEN { len } else { 0 }
+}
+
+
+/// Trim the poly-G tail then the adapter at 3' of reads.
+pub struct Trimmer {
+    // adapters of R1 and R2
+    adapters: [Vec<u8>; 2],
+    // reads shorter than it after trimming are discarded
+    min_len: usize,
+}
+
+impl Trimmer {
+    pub fn new(adapter1: &[u8], adapter2: &[u8], min_len: usize) -> Self {
+        Self { adapters: [adapter1.to_vec(), adapter2.to_vec()], min_len }
+    }
+
+    /// Length of the read after trimming, None if it becomes too short.
+    /// `mate` is 0 for R1 (and single-end reads), 1 for R2.
+    pub fn trim(&self, seq: &[u8], mate: usize, counter: &mut TrimCounter) -> Option<usize> {
+        counter.reads += 1;
+        let mut len = seq.len() - poly_g_len(seq);
+        if len < seq.len() { counter.poly_g += 1 }
+        if let Some(pos) = adapter_pos(&seq[..len], &self.adapters[mate]) {
+            counter.adapter += 1;
+            len = pos;
+        }
+        if len < seq.len() && len < self.min_len {
+            counter.too_short += 1;
+            return None
+        }
+        Some(len)
+    }
+}
+
+
+pub struct TrimCounter {
+    reads: u64,
+    adapter: u64,
+    poly_g: u64,
+    too_short: u64,
+}
+
+impl TrimCounter {
+    pub fn new() -> Self {
+        Self { reads: 0, adapter: 0, poly_g: 0, too_short: 0 }
+    }
+
+    pub fn merge(&mut self, other: &TrimCounter) {
+        self.reads += other.reads;
+        self.adapter += other.adapter;
+        self.poly_g += other.poly_g;
+        self.too_short += other.too_short;
+    }
+}
+
+impl fmt::Display for TrimCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ratio = |c| {
+            if self.reads == 0 { return "0%".to_string(); }
+            format!("{:.2}%", ((c*100) as f64) / (self.reads as f64))
+        };
+        write!(f,
+            "Trim result:
+    with adapter\t{}\t{}
+    with poly-G\t{}\t{}
+    too short after trim\t{}\t{}
+total reads: {}\n",
+            self.adapter, ratio(self.adapter),
+            self.poly_g, ratio(self.poly_g),
+            self.too_short, ratio(self.too_short),
+            self.reads,
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSERT: &[u8] = b"CCCCTTTTTCACACCCTTTT";
+
+    fn read(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn test_adapter_pos() {
+        let adapter = ADAPTER_R1.as_bytes();
+        assert_eq!(adapter_pos(&read(&[INSERT, adapter, b"ACGT"]), adapter), Some(20));
+        // 3 mismatches in 33 bases
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGTTCGGAAGAGCACACGACTGAACTCCTGTCA"]), adapter), Some(20));
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGTTCGGTAGAGCACACGACTGAACTCCTGTCA"]), adapter), None);
+        // partial adapter at read end
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGATCGGA"]), adapter), Some(20));
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGATC"]), adapter), Some(20));
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGAT"]), adapter), None);
+        // no mismatch allowed in short overlap
+        assert_eq!(adapter_pos(&read(&[INSERT, b"AGTTCG"]), adapter), None);
+        assert_eq!(adapter_pos(INSERT, adapter), None);
+    }
+
+    #[test]
+    fn test_poly_g_len() {
+        assert_eq!(poly_g_len(&read(&[INSERT, &[b'G'; 15]])), 15);
+        // errors in the tail, one in each 8 bases
+        assert_eq!(poly_g_len(&read(&[INSERT, b"GGGGGTGGGGGG"])), 12);
+        assert_eq!(poly_g_len(&read(&[INSERT, b"GGGGGGGTGGGGGGGTGGGG"])), 20);
+        // too many errors, the tail stops before them
+        assert_eq!(poly_g_len(&read(&[INSERT, b"GAGAGAGGGGGGGGGGG"])), 13);
+        assert_eq!(poly_g_len(&read(&[INSERT, b"GGGGGGG"])), 0);
+        assert_eq!(poly_g_len(INSERT), 0);
+    }
+
+    #[test]
+    fn test_trim() {
+        let trimmer = Trimmer::new(ADAPTER_R1.as_bytes(), ADAPTER_R2.as_bytes(), 15);
+        let mut counter = TrimCounter::new();
+        assert_eq!(trimmer.trim(&read(&[INSERT, ADAPTER_R1.as_bytes()]), 0, &mut counter), Some(20));
+        // the adapter of the other mate is not trimmed
+        assert_eq!(trimmer.trim(&read(&[INSERT, ADAPTER_R1.as_bytes()]), 1, &mut counter), Some(53));
+        assert_eq!(trimmer.trim(&read(&[INSERT, ADAPTER_R2.as_bytes()]), 1, &mut counter), Some(20));
+        // poly-G tail after the partial adapter
+        assert_eq!(trimmer.trim(&read(&[INSERT, b"AGATCGGA", &[b'G'; 20]]), 0, &mut counter), Some(20));
+        assert_eq!((counter.reads, counter.adapter, counter.poly_g, counter.too_short), (4, 3, 1, 0));
+    }
+
+    #[test]
+    fn test_trim_too_short() {
+        let trimmer = Trimmer::new(ADAPTER_R1.as_bytes(), ADAPTER_R2.as_bytes(), 25);
+        let mut counter = TrimCounter::new();
+        assert_eq!(trimmer.trim(&read(&[INSERT, ADAPTER_R1.as_bytes()]), 0, &mut counter), None);
+        assert_eq!(trimmer.trim(&read(&[INSERT, &[b'G'; 20]]), 0, &mut counter), None);
+        // short reads not trimmed are kept
+        assert_eq!(trimmer.trim(INSERT, 0, &mut counter), Some(20));
+        assert_eq!((counter.reads, counter.too_short), (3, 2));
+    }
+}